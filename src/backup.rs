@@ -0,0 +1,314 @@
+use std::{
+    fs,
+    io::ErrorKind,
+    path::{Component, Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use chrono::DateTime;
+use serde::{Deserialize, Serialize};
+
+use crate::{error::ApplicationError, journal, plan::Operation, state};
+
+const MANIFEST: &str = "manifest.toml";
+
+/// Directory holding every backup set, `backups` within the state directory of this machine.
+pub(crate) fn store_dir() -> Result<PathBuf, ApplicationError> {
+    Ok(state::state_dir()?.join("backups"))
+}
+
+/// Files moved out of the way by a single run, stored within a directory named after the time
+/// of the run.
+///
+/// Nothing is written until a backup is performed, so runs without backups leave no trace.
+#[derive(Debug)]
+pub(crate) struct BackupSet {
+    pub(crate) id: String,
+    pub(crate) directory: PathBuf,
+    created: u64,
+}
+
+/// Record of a backup set, stored as `manifest.toml` within its directory.
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Manifest {
+    /// Seconds since the unix epoch
+    pub(crate) created: u64,
+    #[serde(default, rename = "backup")]
+    pub(crate) backups: Vec<Backup>,
+}
+
+/// A single file within a backup set.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Backup {
+    /// Location the file was moved away from
+    pub(crate) original: PathBuf,
+    /// Location of the file relative to the backup set
+    pub(crate) stored: PathBuf,
+    /// Seconds since the unix epoch
+    pub(crate) time: u64,
+    /// Command which replaced the file
+    pub(crate) operation: String,
+}
+
+impl BackupSet {
+    /// Starts a new backup set within `store`, named after the current time.
+    pub(crate) fn new(store: &Path) -> Self {
+        let created = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let timestamp = format_timestamp(created);
+        let mut id = timestamp.clone();
+        let mut suffix = 1;
+        while fs::symlink_metadata(store.join(&id)).is_ok() {
+            id = format!("{timestamp}-{suffix}");
+            suffix += 1;
+        }
+        BackupSet {
+            directory: store.join(&id),
+            id,
+            created,
+        }
+    }
+
+    /// Location within the set where `original` is backed up, mirroring its absolute path.
+    pub(crate) fn path_for(&self, original: &Path) -> PathBuf {
+        let relative = original
+            .components()
+            .filter(|component| matches!(component, Component::Normal(_)))
+            .collect::<PathBuf>();
+        self.directory.join("files").join(relative)
+    }
+
+    /// Writes the manifest for every backup among the executed operations, if there are any.
+    pub(crate) fn write_manifest(
+        &self,
+        operations: &[Operation],
+        operation: &str,
+    ) -> Result<(), ApplicationError> {
+        let backups = operations
+            .iter()
+            .filter_map(|executed| match executed {
+                Operation::Backup { from, to } => Some(Backup {
+                    original: from.clone(),
+                    stored: to.strip_prefix(&self.directory).ok()?.to_path_buf(),
+                    time: self.created,
+                    operation: operation.to_string(),
+                }),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        if backups.is_empty() {
+            return Ok(());
+        }
+
+        let manifest = Manifest {
+            created: self.created,
+            backups,
+        };
+        let content = toml::to_string(&manifest).map_err(|err| {
+            ApplicationError::InvalidManifest(self.directory.clone(), err.to_string())
+        })?;
+        let path = self.directory.join(MANIFEST);
+        fs::create_dir_all(&self.directory).map_err(|err| {
+            ApplicationError::CouldNotCreateDirectories(self.directory.clone(), err)
+        })?;
+        fs::write(&path, content).map_err(|err| ApplicationError::FailedWritingToFile(path, err))
+    }
+}
+
+/// Every backup set within the store together with its manifest, oldest first.
+///
+/// Directories without a manifest belong to runs which never completed and are skipped.
+pub(crate) fn list(store: &Path) -> Result<Vec<(String, Manifest)>, ApplicationError> {
+    let entries = match fs::read_dir(store) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(ApplicationError::CouldNotOpenFile(store.to_path_buf(), err)),
+    };
+    let mut sets = Vec::new();
+    for entry in entries {
+        let entry =
+            entry.map_err(|err| ApplicationError::CouldNotOpenFile(store.to_path_buf(), err))?;
+        let path = entry.path().join(MANIFEST);
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == ErrorKind::NotFound => continue,
+            Err(err) => return Err(ApplicationError::CouldNotOpenFile(path, err)),
+        };
+        let manifest = toml::from_str::<Manifest>(&content)
+            .map_err(|err| ApplicationError::InvalidManifest(path, err.to_string()))?;
+        sets.push((entry.file_name().to_string_lossy().into_owned(), manifest));
+    }
+    sets.sort_by(|(a_id, a), (b_id, b)| (a.created, a_id).cmp(&(b.created, b_id)));
+    Ok(sets)
+}
+
+/// Moves the files of a backup set back to their original location.
+///
+/// Files currently at those locations are backed up into a new set first, which makes the
+/// restore itself reversible. Returns the id of the new set, if anything had to be backed up.
+pub(crate) fn restore(
+    store: &Path,
+    id: &str,
+    journal_path: &Path,
+) -> Result<Option<String>, ApplicationError> {
+    let (_, manifest) = list(store)?
+        .into_iter()
+        .find(|(set_id, _)| set_id == id)
+        .ok_or(ApplicationError::BackupNotFound(id.to_string()))?;
+    let directory = store.join(id);
+
+    let replaced = BackupSet::new(store);
+    let mut operations = Vec::new();
+    for backup in &manifest.backups {
+        if fs::symlink_metadata(&backup.original).is_ok() {
+            operations.push(Operation::Backup {
+                from: backup.original.clone(),
+                to: replaced.path_for(&backup.original),
+            });
+        }
+        operations.push(Operation::Restore {
+            from: directory.join(&backup.stored),
+            to: backup.original.clone(),
+        });
+    }
+    journal::execute(journal_path, &operations, None)?;
+    replaced.write_manifest(&operations, &format!("restore {id}"))?;
+
+    fs::remove_dir_all(&directory)
+        .map_err(|err| ApplicationError::FailedRemovingFile(directory.clone(), err))?;
+    Ok(operations
+        .iter()
+        .any(|operation| matches!(operation, Operation::Backup { .. }))
+        .then_some(replaced.id))
+}
+
+/// Removes backup sets older than `older_than` or beyond the `keep` most recent ones.
+///
+/// Returns the ids of the removed sets.
+pub(crate) fn prune(
+    store: &Path,
+    older_than: Option<Duration>,
+    keep: Option<usize>,
+) -> Result<Vec<String>, ApplicationError> {
+    if older_than.is_none() && keep.is_none() {
+        return Err(ApplicationError::PruneCriteriaRequired);
+    }
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let sets = list(store)?;
+    let count = sets.len();
+    let mut removed = Vec::new();
+    for (index, (id, manifest)) in sets.into_iter().enumerate() {
+        let too_old = older_than
+            .is_some_and(|older_than| now.saturating_sub(manifest.created) > older_than.as_secs());
+        let beyond_kept = keep.is_some_and(|keep| index + keep < count);
+        if too_old || beyond_kept {
+            let directory = store.join(&id);
+            fs::remove_dir_all(&directory)
+                .map_err(|err| ApplicationError::FailedRemovingFile(directory, err))?;
+            removed.push(id);
+        }
+    }
+    Ok(removed)
+}
+
+/// Formats seconds since the unix epoch as a UTC timestamp usable as a file name,
+/// e.g. `2024-01-31T13-05-09`.
+pub(crate) fn format_timestamp(secs: u64) -> String {
+    DateTime::from_timestamp(secs as i64, 0)
+        .unwrap_or_default()
+        .format("%Y-%m-%dT%H-%M-%S")
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_fs::TempDir;
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(format_timestamp(0), "1970-01-01T00-00-00");
+        assert_eq!(format_timestamp(951782400), "2000-02-29T00-00-00");
+        assert_eq!(format_timestamp(1706706309), "2024-01-31T13-05-09");
+    }
+
+    #[test]
+    fn test_backup_and_restore() {
+        let tmp_dir = TempDir::new().unwrap();
+        let store = tmp_dir.join("backups");
+        let journal_path = tmp_dir.join("journal");
+        let file = tmp_dir.join("file");
+        fs::write(&file, "original").unwrap();
+
+        let set = BackupSet::new(&store);
+        let operations = vec![Operation::Backup {
+            from: file.clone(),
+            to: set.path_for(&file),
+        }];
+        journal::execute(&journal_path, &operations, None).unwrap();
+        set.write_manifest(&operations, "sync").unwrap();
+        fs::write(&file, "replacement").unwrap();
+
+        let sets = list(&store).unwrap();
+        assert_eq!(sets.len(), 1);
+        assert_eq!(sets[0].0, set.id);
+        assert_eq!(sets[0].1.backups[0].original, file);
+        assert_eq!(sets[0].1.backups[0].operation, "sync");
+
+        let replaced = restore(&store, &set.id, &journal_path).unwrap().unwrap();
+        assert_eq!(fs::read_to_string(&file).unwrap(), "original");
+        let sets = list(&store).unwrap();
+        assert_eq!(sets.len(), 1);
+        assert_eq!(sets[0].0, replaced);
+        assert_eq!(
+            fs::read_to_string(store.join(&replaced).join(&sets[0].1.backups[0].stored)).unwrap(),
+            "replacement"
+        );
+
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_prune() {
+        let tmp_dir = TempDir::new().unwrap();
+        let store = tmp_dir.join("backups");
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        for (id, created) in [
+            ("old", now - 10 * 86400),
+            ("recent", now - 60),
+            ("new", now),
+        ] {
+            fs::create_dir_all(store.join(id)).unwrap();
+            let manifest = Manifest {
+                created,
+                backups: Vec::new(),
+            };
+            fs::write(
+                store.join(id).join(MANIFEST),
+                toml::to_string(&manifest).unwrap(),
+            )
+            .unwrap();
+        }
+
+        assert!(prune(&store, None, None).is_err());
+        assert_eq!(
+            prune(&store, Some(Duration::from_secs(86400)), None).unwrap(),
+            vec!["old".to_string()]
+        );
+        assert_eq!(
+            prune(&store, None, Some(1)).unwrap(),
+            vec!["recent".to_string()]
+        );
+        assert_eq!(list(&store).unwrap().len(), 1);
+
+        tmp_dir.close().unwrap();
+    }
+}
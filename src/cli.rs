@@ -2,8 +2,12 @@ use std::path::PathBuf;
 
 use clap::{Args, Parser, ValueEnum};
 
+use crate::{
+    conflict::ConflictStrategy, crypto::KeyAlgorithm, error::EXIT_CODES_HELP, register::LinkMode,
+};
+
 #[derive(Parser, Debug)]
-#[clap(name = "Dotfile Manager")]
+#[clap(name = "Dotfile Manager", after_help = EXIT_CODES_HELP)]
 pub(crate) struct Cli {
     /// The primary action for the application
     pub(crate) primary_action: PrimaryAction,
@@ -14,18 +18,66 @@ pub(crate) struct Cli {
         required = false,
         required_if_eq("primary_action", "sync")
     )]
-    pub(crate) sync_direction: SyncDirection,
+    pub(crate) sync_direction: Option<SyncDirection>,
     /// File type to be added into tracked files
     #[clap(short='F', long, required=false, required_if_eq_any=[("primary_action", "add"), ("primary_action", "remove")])]
-    pub(crate) file_type: FileType,
-    /// File input, used to define a file to be added or removed from dotfiles
-    #[clap(short = 'f', long, requires = "file_type", required_if_eq_any=[("primary_action", "add"), ("primary_action", "remove")])]
+    pub(crate) file_type: Option<FileType>,
+    /// File input, used to define a file to be added or removed from dotfiles, the public key of
+    /// an added recipient or the file an exported public key is written to
+    #[clap(short = 'f', long, required_if_eq_any=[("primary_action", "add"), ("primary_action", "remove")])]
     pub(crate) file: Option<PathBuf>,
     /// PGP key which has different use cases depending on the function
     #[clap(short = 'k', long)]
     pub(crate) secret_key: Option<PathBuf>,
     #[clap(long)]
     pub(crate) source_control_directory: Option<PathBuf>,
+    /// Only sync or show entries carrying the tag, or attach the tag to an added entry
+    #[clap(short = 't', long = "tag")]
+    pub(crate) tags: Vec<String>,
+    /// Home directory of the machine the registers were written on, entries within it are moved
+    /// into the current home directory by migrate
+    #[clap(long)]
+    pub(crate) old_home: Option<PathBuf>,
+    /// How an added entry is linked onto the filesystem
+    #[clap(long)]
+    pub(crate) link_mode: Option<LinkMode>,
+    /// Only remove the entry from the register, leaving the file within the source control and
+    /// the symlink on the filesystem
+    #[clap(long)]
+    pub(crate) keep_in_repo: bool,
+    /// Operation on the backups made by syncs or on the recipients of secrets
+    #[clap(required_if_eq_any=[("primary_action", "backups"), ("primary_action", "recipients")])]
+    pub(crate) action: Option<Action>,
+    /// Backup to restore, as printed by `backups list`, or fingerprint of the recipient to remove,
+    /// as printed by `recipients list`
+    #[clap(long, required_if_eq_any=[("action", "restore"), ("action", "remove")])]
+    pub(crate) id: Option<String>,
+    /// Prune backups older than the given number of days
+    #[clap(long)]
+    pub(crate) older_than: Option<u64>,
+    /// Prune all but the given number of most recent backups
+    #[clap(long)]
+    pub(crate) keep: Option<usize>,
+    /// How files differing from their copy within the source control are handled by a sync
+    /// towards the filesystem
+    #[clap(long, value_enum, default_value_t)]
+    pub(crate) strategy: ConflictStrategy,
+    /// Take the copy within the source control for every conflict without asking
+    #[clap(short = 'y', long, conflicts_with = "strategy")]
+    pub(crate) yes: bool,
+    /// Print the operations a sync would perform without touching the filesystem
+    #[clap(long)]
+    pub(crate) dry_run: bool,
+    /// Algorithm of the key generated by create-key or rotate-key
+    #[clap(long, value_enum, default_value_t)]
+    pub(crate) key_type: KeyAlgorithm,
+    /// User id of the generated key, e.g. "Jane Doe <jane@example.com>", named after the machine
+    /// when omitted
+    #[clap(long)]
+    pub(crate) user_id: Option<String>,
+    /// Number of days the generated key is valid for, never expiring when omitted
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..=36500))]
+    pub(crate) expires_in: Option<u32>,
 }
 
 #[derive(Debug, Args)]
@@ -45,12 +97,42 @@ pub(crate) enum PrimaryAction {
     Add,
     Remove,
     CreateKey,
+    Status,
+    /// Convert registers into the structured format with paths relative to the home directory
+    Migrate,
+    /// List, restore or prune the backups of files replaced by a sync
+    Backups,
+    /// Sync secrets and copied files into the source control whenever they change
+    Watch,
+    /// List, add or remove the public keys every secret is encrypted to besides the secret key
+    Recipients,
+    /// Re-encrypt every secret with a newly generated key, archiving the current key
+    RotateKey,
+    /// Print the public part of the secret key, or write it to the given file, to be added as a
+    /// recipient on another machine
+    ExportKey,
+}
+
+#[derive(Debug, ValueEnum, Clone)]
+pub(crate) enum Action {
+    /// List the backups or recipients
+    List,
+    /// Restore a backup
+    Restore,
+    /// Remove old backups
+    Prune,
+    /// Add a recipient and re-encrypt every secret
+    Add,
+    /// Remove a recipient and re-encrypt every secret
+    Remove,
 }
 
 #[derive(Debug, ValueEnum, Clone)]
 pub(crate) enum SyncDirection {
     Dotfiles,
     Filesystem,
+    /// Decide per entry, taking the side which changed since the entry was last synced
+    Auto,
 }
 
 #[derive(Debug, ValueEnum, Clone)]
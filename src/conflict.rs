@@ -0,0 +1,184 @@
+use std::{fs, io::ErrorKind, path::Path};
+
+use clap::ValueEnum;
+use similar::TextDiff;
+
+use crate::{dotignore::DotIgnore, error::ApplicationError, prompt};
+
+/// How a file on the filesystem differing from its copy within the source control is handled.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub(crate) enum ConflictStrategy {
+    /// Ask for every conflicting file
+    #[default]
+    Ask,
+    /// Move the file on the filesystem into the source control, replacing its copy
+    KeepLocal,
+    /// Back up the file on the filesystem and link the copy within the source control
+    TakeRepo,
+    /// Leave the file on the filesystem untouched
+    Skip,
+}
+
+/// Decision taken for a single conflicting file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Resolution {
+    KeepLocal,
+    TakeRepo,
+    Skip,
+}
+
+/// Resolves conflicts according to a strategy, prompting when it is [`ConflictStrategy::Ask`].
+#[derive(Debug, Default)]
+pub(crate) struct Resolver {
+    pub(crate) strategy: ConflictStrategy,
+    /// Nothing is prompted during a dry-run, conflicts are reported and skipped instead
+    pub(crate) dry_run: bool,
+}
+
+impl Resolver {
+    pub(crate) fn resolve(
+        &self,
+        file: &Path,
+        dotfile: &Path,
+    ) -> Result<Resolution, ApplicationError> {
+        match self.strategy {
+            ConflictStrategy::KeepLocal => return Ok(Resolution::KeepLocal),
+            ConflictStrategy::TakeRepo => return Ok(Resolution::TakeRepo),
+            ConflictStrategy::Skip => return Ok(Resolution::Skip),
+            ConflictStrategy::Ask if self.dry_run => {
+                println!(
+                    "'{}' differs from the source control, it is resolved when syncing",
+                    file.display()
+                );
+                return Ok(Resolution::Skip);
+            }
+            ConflictStrategy::Ask => {}
+        }
+
+        let question = format!(
+            "'{}' differs from the source control\n[l]keep local, [r]take repo, [d]show diff or [S]kip?\n> ",
+            file.display()
+        );
+        loop {
+            match prompt(&question)?.as_str() {
+                "l" | "keep local" => return Ok(Resolution::KeepLocal),
+                "r" | "take repo" => return Ok(Resolution::TakeRepo),
+                "d" | "show diff" => print_diff(file, dotfile)?,
+                _ => return Ok(Resolution::Skip),
+            }
+        }
+    }
+}
+
+/// Whether two files, or two directories file by file, have the same content.
+pub(crate) fn identical(file: &Path, dotfile: &Path) -> Result<bool, ApplicationError> {
+    let (metadata, dotfile_metadata) = (symlink_metadata(file)?, symlink_metadata(dotfile)?);
+    if metadata.is_dir() && dotfile_metadata.is_dir() {
+        let (walk, dotfile_walk) = (
+            DotIgnore::default().walk(file)?,
+            DotIgnore::default().walk(dotfile)?,
+        );
+        if walk.files != dotfile_walk.files {
+            return Ok(false);
+        }
+        for relative in &walk.files {
+            if !identical(&file.join(relative), &dotfile.join(relative))? {
+                return Ok(false);
+            }
+        }
+        return Ok(true);
+    }
+    if !metadata.is_file()
+        || !dotfile_metadata.is_file()
+        || metadata.len() != dotfile_metadata.len()
+    {
+        return Ok(false);
+    }
+    Ok(read(file)? == read(dotfile)?)
+}
+
+/// Prints a unified diff from the copy within the source control to the file on the filesystem.
+fn print_diff(file: &Path, dotfile: &Path) -> Result<(), ApplicationError> {
+    let pairs = match symlink_metadata(file)?.is_dir() {
+        true => {
+            let mut files = DotIgnore::default().walk(file)?.files;
+            if symlink_metadata(dotfile)?.is_dir() {
+                files.extend(DotIgnore::default().walk(dotfile)?.files);
+            }
+            files.sort();
+            files.dedup();
+            files
+                .iter()
+                .map(|relative| (file.join(relative), dotfile.join(relative)))
+                .collect()
+        }
+        false => vec![(file.to_path_buf(), dotfile.to_path_buf())],
+    };
+    for (file, dotfile) in pairs {
+        let (content, dotfile_content) = (read_or_empty(&file)?, read_or_empty(&dotfile)?);
+        if content == dotfile_content {
+            continue;
+        }
+        match (
+            String::from_utf8(dotfile_content),
+            String::from_utf8(content),
+        ) {
+            (Ok(old), Ok(new)) => print!(
+                "{}",
+                TextDiff::from_lines(&old, &new)
+                    .unified_diff()
+                    .header(&dotfile.to_string_lossy(), &file.to_string_lossy())
+            ),
+            _ => println!(
+                "Binary files '{}' and '{}' differ",
+                dotfile.display(),
+                file.display()
+            ),
+        }
+    }
+    Ok(())
+}
+
+fn symlink_metadata(path: &Path) -> Result<fs::Metadata, ApplicationError> {
+    fs::symlink_metadata(path)
+        .map_err(|err| ApplicationError::CouldNotOpenFile(path.to_path_buf(), err))
+}
+
+fn read(path: &Path) -> Result<Vec<u8>, ApplicationError> {
+    fs::read(path).map_err(|err| ApplicationError::CouldNotOpenFile(path.to_path_buf(), err))
+}
+
+/// Content of a file, empty when it only exists on one side of a directory diff.
+fn read_or_empty(path: &Path) -> Result<Vec<u8>, ApplicationError> {
+    match fs::read(path) {
+        Ok(content) => Ok(content),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+        Err(err) => Err(ApplicationError::CouldNotOpenFile(path.to_path_buf(), err)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_fs::TempDir;
+
+    #[test]
+    fn test_identical() {
+        let tmp_dir = TempDir::new().unwrap();
+        let file = tmp_dir.join("dir").join("file");
+        let dotfile = tmp_dir.join("repo").join("file");
+        fs::create_dir_all(file.parent().unwrap()).unwrap();
+        fs::create_dir_all(dotfile.parent().unwrap()).unwrap();
+        fs::write(&file, "content").unwrap();
+        fs::write(&dotfile, "content").unwrap();
+
+        assert!(identical(&file, &dotfile).unwrap());
+        assert!(identical(&tmp_dir.join("dir"), &tmp_dir.join("repo")).unwrap());
+
+        fs::write(&dotfile, "changed").unwrap();
+        assert!(!identical(&file, &dotfile).unwrap());
+        assert!(!identical(&tmp_dir.join("dir"), &tmp_dir.join("repo")).unwrap());
+
+        tmp_dir.close().unwrap();
+    }
+}
@@ -0,0 +1,923 @@
+use std::{
+    cell::OnceCell,
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{ErrorKind, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use pgp::crypto::{hash::HashAlgorithm, public_key::PublicKeyAlgorithm};
+use pgp::packet::{PacketTrait, Subpacket, SubpacketData};
+use pgp::types::{KeyId, KeyTrait, Mpi, PublicKeyTrait, SecretKeyTrait};
+use pgp::{packet, Deserializable, Message, SignedKeyDetails, SignedPublicKey, SignedSecretKey};
+use rand::{CryptoRng, Rng};
+
+use crate::{create_missing_parents, error::ApplicationError};
+
+/// Environment variable holding the passphrase of the secret key.
+pub(crate) const PASSPHRASE_VARIABLE: &str = "DOTFILES_PASSPHRASE";
+/// Environment variable naming a program which prints the passphrase of the secret key.
+pub(crate) const ASKPASS_VARIABLE: &str = "DOTFILES_ASKPASS";
+
+/// A secret key read from disk, together with the recipients secrets are encrypted to besides
+/// the key itself.
+///
+/// The passphrase is only asked for once the key is first unlocked, and is kept for the rest of
+/// the run. Encryption only needs the public part and never asks, unless an existing encrypted
+/// copy without recorded hashes is compared.
+pub(crate) struct Key {
+    pub(crate) key: SignedSecretKey,
+    pub(crate) recipients: Vec<Recipient>,
+    path: PathBuf,
+    passphrase: OnceCell<String>,
+    passphrase_source: PassphraseSource,
+}
+
+impl Key {
+    /// Hex encoded fingerprint of the key.
+    pub(crate) fn fingerprint(&self) -> String {
+        format_fingerprint(&self.key.fingerprint())
+    }
+
+    /// Public part of the key, as shared with others to add it as a recipient.
+    pub(crate) fn public_key(&self) -> Result<SignedPublicKey, ApplicationError> {
+        let passphrase = self.passphrase()?;
+        let mut public_key = self
+            .key
+            .public_key()
+            .sign(&self.key, || passphrase.to_string())
+            .map_err(|_| ApplicationError::PGPKeySignError(self.path.clone()))?;
+        /* The expiration of the key is not carried over by signing its public part */
+        if let Some(expiration) = self.key.details.key_expiration_time() {
+            let seconds = u32::try_from(expiration.num_seconds()).unwrap_or(u32::MAX);
+            sign_expiration(
+                &mut public_key.details,
+                &self.key.primary_key,
+                seconds,
+                passphrase,
+            )
+            .map_err(|_| ApplicationError::PGPKeySignError(self.path.clone()))?;
+        }
+        Ok(public_key)
+    }
+
+    /// Armored public part of the key, as added by `recipients add -f` on another machine.
+    pub(crate) fn armored_public_key(&self) -> Result<String, ApplicationError> {
+        self.public_key()?
+            .to_armored_string(None)
+            .map_err(|err| ApplicationError::PGPWriterError(self.path.clone(), err))
+    }
+
+    /// Date the key expires at, `None` for a key which never expires.
+    pub(crate) fn expires_at(&self) -> Option<DateTime<Utc>> {
+        self.key.expires_at()
+    }
+
+    /// Keys every secret is encrypted to, the key itself followed by every other recipient.
+    fn encryption_keys(&self) -> Result<Vec<EncryptionKey>, ApplicationError> {
+        let own = EncryptionKey::select(
+            self.key.primary_key.public_key(),
+            self.key
+                .secret_subkeys
+                .iter()
+                .map(|subkey| subkey.key.public_key()),
+        )
+        .ok_or(ApplicationError::KeyCannotEncrypt(self.path.clone()))?;
+        let mut keys = vec![own];
+        for recipient in &self.recipients {
+            if recipient.fingerprint() != self.fingerprint() {
+                keys.push(recipient.encryption_key()?);
+            }
+        }
+        Ok(keys)
+    }
+
+    /// Asks for the passphrase now rather than on first use, failing if it is incorrect.
+    pub(crate) fn unlock(&self) -> Result<(), ApplicationError> {
+        self.passphrase().map(|_| ())
+    }
+
+    /// Passphrase unlocking the key, empty for a key which is not protected.
+    fn passphrase(&self) -> Result<&str, ApplicationError> {
+        if let Some(passphrase) = self.passphrase.get() {
+            return Ok(passphrase);
+        }
+        let passphrase = match self.key.primary_key.secret_params().is_encrypted() {
+            true => read_passphrase(
+                &format!(
+                    "Please input the passphrase to unlock '{}'",
+                    self.path.display()
+                ),
+                &self.passphrase_source,
+            )?,
+            false => String::new(),
+        };
+        self.key
+            .unlock(|| passphrase.clone(), |_| Ok(()))
+            .map_err(|_| ApplicationError::IncorrectPassphrase(self.path.clone()))?;
+        Ok(self.passphrase.get_or_init(|| passphrase))
+    }
+}
+
+/// Reads an armored secret key from disk, along with the recipients within `recipients_dir`.
+pub(crate) fn load_key(key_path: &Path, recipients_dir: &Path) -> Result<Key, ApplicationError> {
+    let key_file = File::open(key_path)
+        .map_err(|err| ApplicationError::CouldNotOpenFile(key_path.to_path_buf(), err))?;
+    Ok(Key {
+        key: SignedSecretKey::from_armor_single(key_file)
+            .map_err(|err| ApplicationError::FailedReadingKey(key_path.to_path_buf(), err))?
+            .0,
+        recipients: load_recipients(recipients_dir)?,
+        path: key_path.to_path_buf(),
+        passphrase: OnceCell::new(),
+        passphrase_source: PassphraseSource::from_env(),
+    })
+}
+
+/// Algorithm a new key is generated with.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub(crate) enum KeyAlgorithm {
+    /// EdDSA signing key with a Curve25519 encryption subkey
+    #[default]
+    Ed25519,
+    /// 3072 bit RSA key with an RSA encryption subkey of the same size
+    Rsa3072,
+    /// 4096 bit RSA key with an RSA encryption subkey of the same size
+    Rsa4096,
+}
+
+impl KeyAlgorithm {
+    /// Types of the primary key and of its encryption subkey.
+    fn key_types(self) -> (pgp::KeyType, pgp::KeyType) {
+        match self {
+            KeyAlgorithm::Ed25519 => (pgp::KeyType::EdDSA, pgp::KeyType::ECDH),
+            KeyAlgorithm::Rsa3072 => (pgp::KeyType::Rsa(3072), pgp::KeyType::Rsa(3072)),
+            KeyAlgorithm::Rsa4096 => (pgp::KeyType::Rsa(4096), pgp::KeyType::Rsa(4096)),
+        }
+    }
+}
+
+impl fmt::Display for KeyAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyAlgorithm::Ed25519 => write!(f, "Ed25519 with a Curve25519 encryption subkey"),
+            KeyAlgorithm::Rsa3072 => write!(f, "RSA 3072 with an RSA 3072 encryption subkey"),
+            KeyAlgorithm::Rsa4096 => write!(f, "RSA 4096 with an RSA 4096 encryption subkey"),
+        }
+    }
+}
+
+/// Parameters of a newly generated key.
+#[derive(Debug, Clone)]
+pub(crate) struct KeyParams {
+    pub(crate) algorithm: KeyAlgorithm,
+    pub(crate) user_id: String,
+    /// Number of days the key is valid for, never expiring when unset
+    pub(crate) expires_in: Option<u32>,
+}
+
+/// Generates a new key protected by `passphrase` and writes it armored to `key_path`, which must
+/// not exist yet.
+///
+/// The key file is only created once the key is generated, and is readable by its owner only.
+pub(crate) fn generate_key(
+    key_path: &Path,
+    passphrase: String,
+    params: &KeyParams,
+) -> Result<Key, ApplicationError> {
+    /* Generating a large RSA key takes a while, which is wasted on a path that is taken */
+    if fs::symlink_metadata(key_path).is_ok() {
+        return Err(ApplicationError::CouldNotOpenFile(
+            key_path.to_path_buf(),
+            ErrorKind::AlreadyExists.into(),
+        ));
+    }
+
+    let (primary_type, subkey_type) = params.algorithm.key_types();
+    let key_params = pgp::SecretKeyParamsBuilder::default()
+        .key_type(primary_type)
+        .passphrase(Some(passphrase.clone()))
+        .primary_user_id(params.user_id.clone())
+        .can_create_certificates(true)
+        .can_sign(true)
+        .subkey(
+            pgp::SubkeyParamsBuilder::default()
+                .key_type(subkey_type)
+                .passphrase(Some(passphrase.clone()))
+                .can_encrypt(true)
+                .build()
+                .unwrap(),
+        )
+        .preferred_symmetric_algorithms(
+            vec![pgp::crypto::sym::SymmetricKeyAlgorithm::AES256].into(),
+        )
+        .preferred_hash_algorithms(vec![HashAlgorithm::SHA2_256].into())
+        .preferred_compression_algorithms(vec![pgp::types::CompressionAlgorithm::ZLIB].into())
+        .build()
+        .unwrap();
+
+    let secret_key = key_params
+        .generate()
+        .map_err(ApplicationError::KeyGenerationFailed)?;
+    let mut signed_secret_key = secret_key
+        .sign(|| passphrase.clone())
+        .map_err(|_| ApplicationError::PGPKeySignError(key_path.to_path_buf()))?;
+    if let Some(days) = params.expires_in {
+        sign_expiration(
+            &mut signed_secret_key.details,
+            &signed_secret_key.primary_key,
+            days * 24 * 60 * 60,
+            &passphrase,
+        )
+        .map_err(|_| ApplicationError::PGPKeySignError(key_path.to_path_buf()))?;
+    }
+
+    let armored = signed_secret_key
+        .to_armored_string(None)
+        .map_err(|err| ApplicationError::PGPWriterError(key_path.to_path_buf(), err))?;
+    create_missing_parents(key_path)?;
+    let mut key_file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(key_path)
+        .map_err(|err| ApplicationError::CouldNotOpenFile(key_path.to_path_buf(), err))?;
+    if let Err(err) = key_file.write_all(armored.as_bytes()) {
+        /* A partially written key would block generating it again */
+        let _ = fs::remove_file(key_path);
+        return Err(ApplicationError::FailedWritingToFile(
+            key_path.to_path_buf(),
+            err,
+        ));
+    }
+    Ok(Key {
+        key: signed_secret_key,
+        recipients: Vec::new(),
+        path: key_path.to_path_buf(),
+        passphrase: OnceCell::from(passphrase),
+        passphrase_source: PassphraseSource::from_env(),
+    })
+}
+
+/// Signs the user ids of a key again with an expiration `seconds` after the creation of the key,
+/// which the key generation of pgp cannot set on its own.
+fn sign_expiration(
+    details: &mut SignedKeyDetails,
+    primary_key: &packet::SecretKey,
+    seconds: u32,
+    passphrase: &str,
+) -> Result<(), pgp::errors::Error> {
+    /* pgp holds the expiration of a key as a date relative to the creation of the key */
+    let expiration = DateTime::from_timestamp(i64::from(seconds), 0).unwrap_or_default();
+    for user in &mut details.users {
+        let Some(signature) = user.signatures.first() else {
+            continue;
+        };
+        let mut config = signature.config.clone();
+        config
+            .hashed_subpackets
+            .retain(|subpacket| !matches!(subpacket.data, SubpacketData::KeyExpirationTime(_)));
+        config
+            .hashed_subpackets
+            .push(Subpacket::regular(SubpacketData::KeyExpirationTime(
+                expiration,
+            )));
+        user.signatures = vec![config.sign_certificate(
+            primary_key,
+            || passphrase.to_string(),
+            user.id.tag(),
+            &user.id,
+        )?];
+    }
+    Ok(())
+}
+
+/// Public key of someone every secret is encrypted to, stored within the source control as
+/// `cfg/recipients/<fingerprint>.asc`.
+#[derive(Debug, Clone)]
+pub(crate) struct Recipient {
+    pub(crate) key: SignedPublicKey,
+    pub(crate) path: PathBuf,
+}
+
+impl Recipient {
+    /// Hex encoded fingerprint of the primary key.
+    pub(crate) fn fingerprint(&self) -> String {
+        format_fingerprint(&self.key.fingerprint())
+    }
+
+    pub(crate) fn user_ids(&self) -> Vec<String> {
+        self.key
+            .details
+            .users
+            .iter()
+            .map(|user| user.id.id().to_string())
+            .collect()
+    }
+
+    fn encryption_key(&self) -> Result<EncryptionKey, ApplicationError> {
+        EncryptionKey::select(
+            self.key.primary_key.clone(),
+            self.key
+                .public_subkeys
+                .iter()
+                .map(|subkey| subkey.key.clone()),
+        )
+        .ok_or(ApplicationError::KeyCannotEncrypt(self.path.clone()))
+    }
+}
+
+/// Reads an armored public key, checking its signatures and that secrets can be encrypted to it.
+pub(crate) fn load_recipient(path: &Path) -> Result<Recipient, ApplicationError> {
+    let file = File::open(path)
+        .map_err(|err| ApplicationError::CouldNotOpenFile(path.to_path_buf(), err))?;
+    let (key, _) = SignedPublicKey::from_armor_single(file)
+        .map_err(|err| ApplicationError::InvalidRecipient(path.to_path_buf(), err))?;
+    key.verify()
+        .map_err(|err| ApplicationError::InvalidRecipient(path.to_path_buf(), err))?;
+    let recipient = Recipient {
+        key,
+        path: path.to_path_buf(),
+    };
+    recipient.encryption_key()?;
+    Ok(recipient)
+}
+
+/// Every recipient within `directory` ordered by file name, none when it does not exist.
+pub(crate) fn load_recipients(directory: &Path) -> Result<Vec<Recipient>, ApplicationError> {
+    let entries = match fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => {
+            return Err(ApplicationError::CouldNotOpenFile(
+                directory.to_path_buf(),
+                err,
+            ))
+        }
+    };
+    let mut paths = entries
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| ApplicationError::CouldNotOpenFile(directory.to_path_buf(), err))?;
+    paths.retain(|path| path.extension().is_some_and(|extension| extension == "asc"));
+    paths.sort();
+    paths.iter().map(|path| load_recipient(path)).collect()
+}
+
+fn format_fingerprint(fingerprint: &[u8]) -> String {
+    fingerprint
+        .iter()
+        .map(|byte| format!("{byte:02X}"))
+        .collect()
+}
+
+/// Public key a secret is encrypted to, the first encryption subkey of a key or else its primary
+/// key.
+///
+/// Primary keys and subkeys are distinct types, which cannot be mixed in a single encryption
+/// without this.
+#[derive(Debug)]
+enum EncryptionKey {
+    Primary(packet::PublicKey),
+    Subkey(packet::PublicSubkey),
+}
+
+impl EncryptionKey {
+    fn select(
+        primary: packet::PublicKey,
+        subkeys: impl IntoIterator<Item = packet::PublicSubkey>,
+    ) -> Option<Self> {
+        match subkeys
+            .into_iter()
+            .find(|subkey| subkey.is_encryption_key())
+        {
+            Some(subkey) => Some(EncryptionKey::Subkey(subkey)),
+            None => primary
+                .is_encryption_key()
+                .then_some(EncryptionKey::Primary(primary)),
+        }
+    }
+}
+
+impl KeyTrait for EncryptionKey {
+    fn fingerprint(&self) -> Vec<u8> {
+        match self {
+            EncryptionKey::Primary(key) => key.fingerprint(),
+            EncryptionKey::Subkey(key) => key.fingerprint(),
+        }
+    }
+
+    fn key_id(&self) -> KeyId {
+        match self {
+            EncryptionKey::Primary(key) => key.key_id(),
+            EncryptionKey::Subkey(key) => key.key_id(),
+        }
+    }
+
+    fn algorithm(&self) -> PublicKeyAlgorithm {
+        match self {
+            EncryptionKey::Primary(key) => key.algorithm(),
+            EncryptionKey::Subkey(key) => key.algorithm(),
+        }
+    }
+}
+
+impl PublicKeyTrait for EncryptionKey {
+    fn verify_signature(
+        &self,
+        hash: HashAlgorithm,
+        data: &[u8],
+        sig: &[Mpi],
+    ) -> pgp::errors::Result<()> {
+        match self {
+            EncryptionKey::Primary(key) => key.verify_signature(hash, data, sig),
+            EncryptionKey::Subkey(key) => key.verify_signature(hash, data, sig),
+        }
+    }
+
+    fn encrypt<R: CryptoRng + Rng>(
+        &self,
+        rng: &mut R,
+        plain: &[u8],
+    ) -> pgp::errors::Result<Vec<Mpi>> {
+        match self {
+            EncryptionKey::Primary(key) => key.encrypt(rng, plain),
+            EncryptionKey::Subkey(key) => key.encrypt(rng, plain),
+        }
+    }
+
+    fn to_writer_old(&self, writer: &mut impl std::io::Write) -> pgp::errors::Result<()> {
+        match self {
+            EncryptionKey::Primary(key) => key.to_writer_old(writer),
+            EncryptionKey::Subkey(key) => key.to_writer_old(writer),
+        }
+    }
+}
+
+/// Where the passphrase of a key is taken from before asking for it on the terminal.
+#[derive(Debug, Default)]
+struct PassphraseSource {
+    /// Passphrase given by `DOTFILES_PASSPHRASE`
+    passphrase: Option<String>,
+    /// Program named by `DOTFILES_ASKPASS` which prints the passphrase
+    askpass: Option<String>,
+}
+
+impl PassphraseSource {
+    /// Sources configured within the environment of the process.
+    fn from_env() -> Self {
+        PassphraseSource {
+            passphrase: std::env::var(PASSPHRASE_VARIABLE).ok(),
+            askpass: std::env::var(ASKPASS_VARIABLE).ok(),
+        }
+    }
+}
+
+/// Reads the passphrase from `DOTFILES_PASSPHRASE`, the program named by `DOTFILES_ASKPASS` or
+/// the terminal, in that order, as captured by `source` when the key was loaded.
+fn read_passphrase(prompt: &str, source: &PassphraseSource) -> Result<String, ApplicationError> {
+    if let Some(passphrase) = &source.passphrase {
+        return Ok(passphrase.clone());
+    }
+    if let Some(askpass) = &source.askpass {
+        let output = Command::new(askpass)
+            .arg(prompt)
+            .output()
+            .map_err(|err| ApplicationError::AskpassFailed(askpass.clone(), err.to_string()))?;
+        if !output.status.success() {
+            return Err(ApplicationError::AskpassFailed(
+                askpass.clone(),
+                output.status.to_string(),
+            ));
+        }
+        let passphrase = String::from_utf8(output.stdout).map_err(|_| {
+            ApplicationError::AskpassFailed(
+                askpass.clone(),
+                "output is not UTF8 encoded".to_string(),
+            )
+        })?;
+        let passphrase = passphrase.trim_end_matches(['\r', '\n']);
+        if passphrase.is_empty() {
+            return Err(ApplicationError::AskpassFailed(
+                askpass.clone(),
+                "no passphrase printed".to_string(),
+            ));
+        }
+        return Ok(passphrase.to_string());
+    }
+    rpassword::prompt_password(format!("{prompt}\n> "))
+        .map_err(ApplicationError::FailedReadingInput)
+}
+
+/// Passphrase protecting a new key, from `DOTFILES_PASSPHRASE` or entered twice on the terminal.
+pub(crate) fn new_passphrase() -> Result<String, ApplicationError> {
+    let passphrase = match std::env::var(PASSPHRASE_VARIABLE) {
+        Ok(passphrase) => passphrase,
+        Err(_) => {
+            let passphrase =
+                rpassword::prompt_password("Please input a passphrase to protect the key\n> ")
+                    .map_err(ApplicationError::FailedReadingInput)?;
+            let repeated = rpassword::prompt_password("Please repeat the passphrase\n> ")
+                .map_err(ApplicationError::FailedReadingInput)?;
+            if passphrase != repeated {
+                return Err(ApplicationError::PassphraseMismatch);
+            }
+            passphrase
+        }
+    };
+    match passphrase.is_empty() {
+        true => Err(ApplicationError::PassphraseRequired),
+        false => Ok(passphrase),
+    }
+}
+
+/// Encrypts the content of `from` and writes it as an armored message to `to`.
+pub(crate) fn encrypt_file(from: &Path, to: &Path, key: &Key) -> Result<(), ApplicationError> {
+    let content = fs::read(from)
+        .map_err(|err| ApplicationError::CouldNotOpenFile(from.to_path_buf(), err))?;
+    encrypt(&content, to, key)
+}
+
+/// Encrypts the content of `from` like [`encrypt_file`], unless an existing message at `to`
+/// already decrypts to the same content, since encryption is randomized and would change it
+/// regardless.
+///
+/// Unlike encryption this needs the passphrase of the key.
+pub(crate) fn encrypt_changed_file(
+    from: &Path,
+    to: &Path,
+    key: &Key,
+) -> Result<(), ApplicationError> {
+    let content = fs::read(from)
+        .map_err(|err| ApplicationError::CouldNotOpenFile(from.to_path_buf(), err))?;
+    if to.is_file() {
+        key.unlock()?;
+        if decrypt(to, key).is_ok_and(|existing| existing == content) {
+            return Ok(());
+        }
+    }
+    encrypt(&content, to, key)
+}
+
+/// Encrypts `content` to the key and every recipient, writing it as an armored message to `to`.
+pub(crate) fn encrypt(content: &[u8], to: &Path, key: &Key) -> Result<(), ApplicationError> {
+    let keys = key.encryption_keys()?;
+    let encrypted_content = Message::new_literal_bytes("none", content)
+        .encrypt_to_keys(
+            &mut rand::thread_rng(),
+            pgp::crypto::sym::SymmetricKeyAlgorithm::AES128,
+            &keys.iter().collect::<Vec<_>>(),
+        )
+        .map_err(|err| ApplicationError::FailedEncryptingContent(to.to_path_buf(), err))?;
+    let mut dotfile = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(to)
+        .map_err(|err| ApplicationError::CouldNotOpenFile(to.to_path_buf(), err))?;
+
+    encrypted_content
+        .to_armored_writer(&mut dotfile, None)
+        .map_err(|err| ApplicationError::PGPWriterError(to.to_path_buf(), err))
+}
+
+/// Decrypts the armored message in `from` and writes the plaintext to `to`.
+///
+/// A new plaintext is only readable by its owner. A message without any content is written as an
+/// empty file.
+pub(crate) fn decrypt_file(from: &Path, to: &Path, key: &Key) -> Result<(), ApplicationError> {
+    let content = decrypt(from, key)?;
+    OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(to)
+        .and_then(|mut plaintext| plaintext.write_all(&content))
+        .map_err(|err| ApplicationError::FailedWritingToFile(to.to_path_buf(), err))
+}
+
+/// Decrypts the armored message in `from`, empty when it does not contain any content.
+///
+/// Secrets encrypted by earlier versions are stored as text, whose line endings are normalized
+/// to CRLF, and are returned with LF line endings.
+pub(crate) fn decrypt(from: &Path, key: &Key) -> Result<Vec<u8>, ApplicationError> {
+    let dotfile = File::open(from)
+        .map_err(|err| ApplicationError::CouldNotOpenFile(from.to_path_buf(), err))?;
+    let (message, _) = Message::from_armor_single(dotfile)
+        .map_err(|err| ApplicationError::PGPMessageReadError(from.to_path_buf(), err))?;
+    let passphrase = key.passphrase()?;
+    let (decryptor, _) = message
+        .decrypt(|| passphrase.to_string(), &[&key.key])
+        .map_err(|_| ApplicationError::FailedDecryptingContent(from.to_path_buf()))?;
+
+    for msg in decryptor {
+        let message = msg
+            .map_err(ApplicationError::FailedDecryptingMessageInContent)?
+            .decompress()
+            .map_err(ApplicationError::ErrorReadingContentInMessage)?;
+        let literal = message
+            .get_literal()
+            .ok_or(ApplicationError::NoContentInPGPMessage)?;
+        let content = match literal.is_binary() {
+            true => literal.data().to_vec(),
+            false => {
+                let data = literal.data();
+                data.iter()
+                    .enumerate()
+                    .filter(|&(index, &byte)| byte != b'\r' || data.get(index + 1) != Some(&b'\n'))
+                    .map(|(_, &byte)| byte)
+                    .collect()
+            }
+        };
+        if !content.is_empty() {
+            return Ok(content);
+        }
+    }
+    Ok(Vec::new())
+}
+
+/// Unprotected key signing with EdDSA and encrypting to a Curve25519 subkey, which is generated
+/// far quicker than an RSA key.
+#[cfg(test)]
+pub(crate) fn test_key(path: &Path) -> Key {
+    let key = pgp::SecretKeyParamsBuilder::default()
+        .key_type(pgp::KeyType::EdDSA)
+        .primary_user_id("Test <test@example.com>".to_string())
+        .can_sign(true)
+        .subkey(
+            pgp::SubkeyParamsBuilder::default()
+                .key_type(pgp::KeyType::ECDH)
+                .can_encrypt(true)
+                .build()
+                .unwrap(),
+        )
+        .build()
+        .unwrap()
+        .generate()
+        .unwrap()
+        .sign(String::new)
+        .unwrap();
+    Key {
+        key,
+        recipients: Vec::new(),
+        path: path.to_path_buf(),
+        passphrase: OnceCell::new(),
+        passphrase_source: PassphraseSource::default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_fs::TempDir;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn generate_key(path: &Path) -> Key {
+        let key = pgp::SecretKeyParamsBuilder::default()
+            .key_type(pgp::KeyType::Rsa(2048))
+            .primary_user_id("".to_string())
+            .can_create_certificates(false)
+            .can_sign(true)
+            .build()
+            .unwrap()
+            .generate()
+            .unwrap()
+            .sign(String::new)
+            .unwrap();
+        Key {
+            key,
+            recipients: Vec::new(),
+            path: path.to_path_buf(),
+            passphrase: OnceCell::new(),
+            passphrase_source: PassphraseSource::default(),
+        }
+    }
+
+    #[test]
+    fn test_encrypt_decrypt_round_trip() {
+        let tmp_dir = TempDir::new().unwrap();
+        let key = generate_key(&tmp_dir.join("key"));
+        let encrypted = tmp_dir.join("encrypted");
+        let decrypted = tmp_dir.join("decrypted");
+
+        for content in [b"text secret\n".to_vec(), (0..=255).collect::<Vec<u8>>()] {
+            let plaintext = tmp_dir.join("plaintext");
+            fs::write(&plaintext, &content).unwrap();
+            encrypt_file(&plaintext, &encrypted, &key).unwrap();
+            decrypt_file(&encrypted, &decrypted, &key).unwrap();
+            assert_eq!(fs::read(&decrypted).unwrap(), content);
+
+            // Encrypting unchanged content leaves the randomized message untouched
+            let message = fs::read(&encrypted).unwrap();
+            encrypt_changed_file(&plaintext, &encrypted, &key).unwrap();
+            assert_eq!(fs::read(&encrypted).unwrap(), message);
+            encrypt_file(&plaintext, &encrypted, &key).unwrap();
+            assert_ne!(fs::read(&encrypted).unwrap(), message);
+        }
+        assert_eq!(
+            fs::metadata(&decrypted).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+
+        // Secrets encrypted by earlier versions are stored as text literals
+        let armored = Message::new_literal("none", "legacy secret\n")
+            .encrypt_to_keys(
+                &mut rand::thread_rng(),
+                pgp::crypto::sym::SymmetricKeyAlgorithm::AES128,
+                &[&key.key.public_key()],
+            )
+            .unwrap()
+            .to_armored_string(None)
+            .unwrap();
+        fs::write(&encrypted, armored).unwrap();
+        decrypt_file(&encrypted, &decrypted, &key).unwrap();
+        assert_eq!(fs::read_to_string(&decrypted).unwrap(), "legacy secret\n");
+
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_decrypt_empty_secret() {
+        let tmp_dir = TempDir::new().unwrap();
+        let key = test_key(&tmp_dir.join("key"));
+        let plaintext = tmp_dir.join("plaintext");
+        let encrypted = tmp_dir.join("encrypted");
+        let decrypted = tmp_dir.join("decrypted");
+        fs::write(&plaintext, "").unwrap();
+
+        encrypt_file(&plaintext, &encrypted, &key).unwrap();
+        decrypt_file(&encrypted, &decrypted, &key).unwrap();
+        assert_eq!(fs::read(&decrypted).unwrap(), b"");
+        assert_eq!(
+            fs::metadata(&decrypted).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_encrypt_to_recipients() {
+        let tmp_dir = TempDir::new().unwrap();
+        let mut key = generate_key(&tmp_dir.join("key"));
+        let recipient = test_key(&tmp_dir.join("recipient"));
+        let recipient_path = tmp_dir.join("recipients").join("recipient.asc");
+        fs::create_dir(tmp_dir.join("recipients")).unwrap();
+        fs::write(
+            &recipient_path,
+            recipient
+                .public_key()
+                .unwrap()
+                .to_armored_string(None)
+                .unwrap(),
+        )
+        .unwrap();
+
+        let recipients = load_recipients(&tmp_dir.join("recipients")).unwrap();
+        assert_eq!(recipients.len(), 1);
+        assert_eq!(
+            recipients[0].user_ids(),
+            vec!["Test <test@example.com>".to_string()]
+        );
+        let encrypted = tmp_dir.join("encrypted");
+        encrypt(b"own secret", &encrypted, &key).unwrap();
+        assert!(matches!(
+            decrypt(&encrypted, &recipient),
+            Err(ApplicationError::FailedDecryptingContent(_))
+        ));
+
+        key.recipients = recipients;
+        encrypt(b"shared secret", &encrypted, &key).unwrap();
+        assert_eq!(decrypt(&encrypted, &key).unwrap(), b"shared secret");
+        assert_eq!(decrypt(&encrypted, &recipient).unwrap(), b"shared secret");
+
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_generate_key_with_params() {
+        let tmp_dir = TempDir::new().unwrap();
+        let key_path = tmp_dir.join("key");
+        let params = KeyParams {
+            algorithm: KeyAlgorithm::Ed25519,
+            user_id: "Test <test@example.com>".to_string(),
+            expires_in: Some(30),
+        };
+        let key = super::generate_key(&key_path, "passphrase".to_string(), &params).unwrap();
+        assert_eq!(
+            fs::metadata(&key_path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+        assert_eq!(
+            key.expires_at().unwrap() - *key.key.primary_key.created_at(),
+            chrono::Duration::days(30)
+        );
+        assert_eq!(
+            load_key(&key_path, &tmp_dir.join("recipients"))
+                .unwrap()
+                .fingerprint(),
+            key.fingerprint()
+        );
+        assert!(matches!(
+            super::generate_key(&key_path, String::new(), &params),
+            Err(ApplicationError::CouldNotOpenFile(..))
+        ));
+
+        let recipient = Recipient {
+            key: key.public_key().unwrap(),
+            path: PathBuf::new(),
+        };
+        assert_eq!(recipient.user_ids(), vec![params.user_id.clone()]);
+        assert_eq!(recipient.key.expires_at(), key.expires_at());
+
+        let encrypted = tmp_dir.join("encrypted");
+        encrypt(b"secret", &encrypted, &key).unwrap();
+        assert_eq!(decrypt(&encrypted, &key).unwrap(), b"secret");
+
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_export_public_key() {
+        let tmp_dir = TempDir::new().unwrap();
+        let key = test_key(&tmp_dir.join("key"));
+        let exported = tmp_dir.join("exported.asc");
+        fs::write(&exported, key.armored_public_key().unwrap()).unwrap();
+        assert!(!fs::read_to_string(&exported)
+            .unwrap()
+            .contains("PRIVATE KEY"));
+
+        let mut sender = test_key(&tmp_dir.join("sender"));
+        let recipient = load_recipient(&exported).unwrap();
+        assert_eq!(recipient.fingerprint(), key.fingerprint());
+        sender.recipients.push(recipient);
+        let encrypted = tmp_dir.join("encrypted");
+        encrypt(b"shared secret", &encrypted, &sender).unwrap();
+        assert_eq!(decrypt(&encrypted, &key).unwrap(), b"shared secret");
+
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_passphrase_sources() {
+        let tmp_dir = TempDir::new().unwrap();
+        let key_path = tmp_dir.join("key");
+        let params = KeyParams {
+            algorithm: KeyAlgorithm::Ed25519,
+            user_id: String::new(),
+            expires_in: None,
+        };
+        super::generate_key(&key_path, "passphrase".to_string(), &params).unwrap();
+        let askpass = |name: &str, script: &str| {
+            let path = tmp_dir.join(name);
+            fs::write(&path, format!("#!/bin/sh\n{script}\n")).unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+            path
+        };
+        let correct = askpass("correct", "echo passphrase");
+        let wrong = askpass("wrong", "echo wrong");
+        let failing = askpass("failing", "exit 1");
+        let silent = askpass("silent", "true");
+        let source = |passphrase: Option<&str>, askpass: &Path| PassphraseSource {
+            passphrase: passphrase.map(str::to_string),
+            askpass: Some(askpass.to_str().unwrap().to_string()),
+        };
+        let unlock = |passphrase_source| {
+            Key {
+                passphrase_source,
+                ..load_key(&key_path, &tmp_dir.join("recipients"))?
+            }
+            .unlock()
+        };
+
+        unlock(source(Some("passphrase"), &failing)).unwrap();
+        assert!(matches!(
+            unlock(source(None, &failing)),
+            Err(ApplicationError::AskpassFailed(..))
+        ));
+        assert!(matches!(
+            unlock(source(None, &silent)),
+            Err(ApplicationError::AskpassFailed(..))
+        ));
+        unlock(source(None, &correct)).unwrap();
+        let err = unlock(source(None, &wrong)).unwrap_err();
+        assert!(matches!(err, ApplicationError::IncorrectPassphrase(_)));
+        assert_eq!(err.exit_code(), crate::error::EXIT_CRYPTO);
+
+        // Keys created without a passphrase never ask for one
+        let legacy = Key {
+            passphrase_source: source(None, &failing),
+            ..test_key(&tmp_dir.join("legacy"))
+        };
+        let encrypted = tmp_dir.join("encrypted");
+        encrypt(b"secret", &encrypted, &legacy).unwrap();
+        assert_eq!(decrypt(&encrypted, &legacy).unwrap(), b"secret");
+
+        tmp_dir.close().unwrap();
+    }
+}
@@ -0,0 +1,175 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use ignore::{
+    gitignore::{Gitignore, GitignoreBuilder},
+    Match,
+};
+
+use crate::error::ApplicationError;
+
+/// Name of the files holding ignore patterns, both at the root of the source control and within
+/// tracked directories.
+pub(crate) const DOTIGNORE: &str = ".dotignore";
+
+/// Ignore patterns for files within tracked directories, using the gitignore syntax.
+///
+/// Patterns of the `.dotignore` at the root of the source control apply relative to every tracked
+/// directory, while a `.dotignore` within a tracked directory applies to the directory it is in.
+#[derive(Debug, Default)]
+pub(crate) struct DotIgnore {
+    root: Option<(PathBuf, Vec<String>)>,
+}
+
+/// Files within a tracked directory, relative to it.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct Walk {
+    pub(crate) files: Vec<PathBuf>,
+    /// Ignored files and directories, the content of an ignored directory is not listed
+    pub(crate) ignored: Vec<PathBuf>,
+}
+
+impl DotIgnore {
+    /// Reads the `.dotignore` at the root of the source control, if present.
+    pub(crate) fn load(dotfiles_dir: &Path) -> Result<Self, ApplicationError> {
+        let path = dotfiles_dir.join(DOTIGNORE);
+        match fs::read_to_string(&path) {
+            Ok(content) => Ok(DotIgnore {
+                root: Some((path, content.lines().map(str::to_string).collect())),
+            }),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(DotIgnore::default()),
+            Err(err) => Err(ApplicationError::CouldNotOpenFile(path, err)),
+        }
+    }
+
+    /// Patterns of the root `.dotignore`, anchored at the tracked directory.
+    fn root_matcher(&self, directory: &Path) -> Result<Option<Gitignore>, ApplicationError> {
+        let Some((path, lines)) = &self.root else {
+            return Ok(None);
+        };
+        let mut builder = GitignoreBuilder::new(directory);
+        for line in lines {
+            builder
+                .add_line(Some(path.clone()), line)
+                .map_err(|err| ApplicationError::InvalidIgnoreFile(path.clone(), err))?;
+        }
+        builder
+            .build()
+            .map(Some)
+            .map_err(|err| ApplicationError::InvalidIgnoreFile(path.clone(), err))
+    }
+
+    /// Lists every file below `directory` relative to it, sorted and without following symlinks,
+    /// separating the ignored ones.
+    pub(crate) fn walk(&self, directory: &Path) -> Result<Walk, ApplicationError> {
+        let mut walk = Walk::default();
+        let root = self.root_matcher(directory)?;
+        let mut pending = vec![(PathBuf::new(), root.into_iter().collect::<Vec<_>>())];
+        while let Some((relative, mut matchers)) = pending.pop() {
+            let current = directory.join(&relative);
+            if let Some(matcher) = nested_matcher(&current)? {
+                matchers.push(matcher);
+            }
+            let entries = fs::read_dir(&current)
+                .map_err(|err| ApplicationError::CouldNotOpenFile(current.clone(), err))?;
+            for entry in entries {
+                let entry = entry
+                    .map_err(|err| ApplicationError::CouldNotOpenFile(current.clone(), err))?;
+                let is_dir = entry
+                    .file_type()
+                    .map_err(|err| ApplicationError::CouldNotOpenFile(entry.path(), err))?
+                    .is_dir();
+                let child = relative.join(entry.file_name());
+                if is_ignored(&matchers, &entry.path(), is_dir) {
+                    walk.ignored.push(child);
+                } else if is_dir {
+                    pending.push((child, matchers.clone()));
+                } else {
+                    walk.files.push(child);
+                }
+            }
+        }
+        walk.files.sort();
+        walk.ignored.sort();
+        Ok(walk)
+    }
+}
+
+/// Patterns of the `.dotignore` within `directory`, if present.
+fn nested_matcher(directory: &Path) -> Result<Option<Gitignore>, ApplicationError> {
+    let path = directory.join(DOTIGNORE);
+    if !path.is_file() {
+        return Ok(None);
+    }
+    let mut builder = GitignoreBuilder::new(directory);
+    if let Some(err) = builder.add(&path) {
+        return Err(ApplicationError::InvalidIgnoreFile(path, err));
+    }
+    builder
+        .build()
+        .map(Some)
+        .map_err(|err| ApplicationError::InvalidIgnoreFile(path, err))
+}
+
+/// Whether the innermost `.dotignore` with a matching pattern ignores the path.
+fn is_ignored(matchers: &[Gitignore], path: &Path, is_dir: bool) -> bool {
+    for matcher in matchers.iter().rev() {
+        match matcher.matched(path, is_dir) {
+            Match::Ignore(_) => return true,
+            Match::Whitelist(_) => return false,
+            Match::None => {}
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_fs::TempDir;
+
+    #[test]
+    fn test_walk_honors_root_and_nested_patterns() {
+        let tmp_dir = TempDir::new().unwrap();
+        let dotfiles_dir = tmp_dir.join("dotfiles");
+        let directory = tmp_dir.join("app");
+        fs::create_dir_all(&dotfiles_dir).unwrap();
+        fs::create_dir_all(directory.join("cache")).unwrap();
+        fs::create_dir_all(directory.join("nested")).unwrap();
+        fs::write(dotfiles_dir.join(DOTIGNORE), "*.lock\n/cache/\n").unwrap();
+        fs::write(directory.join("config"), "").unwrap();
+        fs::write(directory.join("app.lock"), "").unwrap();
+        fs::write(directory.join("cache").join("data"), "").unwrap();
+        fs::write(
+            directory.join("nested").join(DOTIGNORE),
+            "history\n!keep.lock\n",
+        )
+        .unwrap();
+        fs::write(directory.join("nested").join("history"), "").unwrap();
+        fs::write(directory.join("nested").join("keep.lock"), "").unwrap();
+
+        let walk = DotIgnore::load(&dotfiles_dir)
+            .unwrap()
+            .walk(&directory)
+            .unwrap();
+        assert_eq!(
+            walk,
+            Walk {
+                files: vec![
+                    PathBuf::from("config"),
+                    PathBuf::from("nested").join(DOTIGNORE),
+                    PathBuf::from("nested").join("keep.lock"),
+                ],
+                ignored: vec![
+                    PathBuf::from("app.lock"),
+                    PathBuf::from("cache"),
+                    PathBuf::from("nested").join("history"),
+                ],
+            }
+        );
+
+        tmp_dir.close().unwrap();
+    }
+}
@@ -38,12 +38,28 @@ pub(crate) enum ApplicationError {
     ErrorExpandingVariable(shellexpand::LookupError<std::env::VarError>),
     #[error("File input is required for the program to function")]
     FileInputRequired,
+    #[error("File type is required for the program to function")]
+    FileTypeRequired,
+    #[error("Sync direction is required for the program to function")]
+    SyncDirectionRequired,
     #[error("$HOME is not defined")]
     UndedfinedHomeVariable,
     #[error("An error has occured while encrypting content of '{0}': '{1}'")]
     FailedEncryptingContent(PathBuf, pgp::errors::Error),
     #[error("An error has occured while reading you pgp key: {0}")]
     FailedReadingKey(PathBuf, pgp::errors::Error),
+    #[error("Key '{0}' has no key usable for encryption")]
+    KeyCannotEncrypt(PathBuf),
+    #[error("Public key '{0}' is invalid: {1}")]
+    InvalidRecipient(PathBuf, pgp::errors::Error),
+    #[error("No recipient matches '{0}', see `recipients list`")]
+    RecipientNotFound(String),
+    #[error("Several recipients match '{0}', use the full fingerprint")]
+    AmbiguousRecipient(String),
+    #[error("Recipient id is required to remove a recipient")]
+    RecipientIdRequired,
+    #[error("'{0}' does not decrypt to its original content once encrypted again, the secrets have been left untouched")]
+    ReencryptionVerificationFailed(PathBuf),
     #[error("Error reading '{0}' containing pgp message to be encrypted: {1}")]
     PGPMessageReadError(PathBuf, pgp::errors::Error),
     #[error("An error has occured while writing information to '{0}': {1}")]
@@ -56,8 +72,193 @@ pub(crate) enum ApplicationError {
     ErrorReadingContentInMessage(pgp::errors::Error),
     #[error("Empty content within decrypted message")]
     NoContentInPGPMessage,
-    #[error("Content within decrypted message is not UTF8 encoded")]
-    MessageNotUTF8Encoded,
+    #[error("Could not remove file '{0}' error '{1}'")]
+    FailedRemovingFile(PathBuf, std::io::Error),
+    #[error("A journal of an interrupted sync exists at '{0}', roll it back or remove it before syncing")]
+    InterruptedSync(PathBuf),
+    #[error("Journal '{0}' is corrupt at line {1}")]
+    CorruptJournal(PathBuf, usize),
+    #[error("Failed reading input from the terminal")]
+    FailedReadingInput(std::io::Error),
+    #[error("Could not set permissions of '{0}' error '{1}'")]
+    FailedSettingPermissions(PathBuf, std::io::Error),
+    #[error("Invalid permissions '{0}', expected an octal file mode such as '600'")]
+    InvalidPermissions(String),
+    #[error("Could not set owner of '{0}' error '{1}'")]
+    FailedSettingOwner(PathBuf, std::io::Error),
+    #[error("Invalid owner '{0}', expected a numeric user id optionally followed by a group id such as '1000:1000'")]
+    InvalidOwner(String),
+    #[error("Register '{0}' is invalid: {1}")]
+    InvalidRegister(PathBuf, String),
+    #[error("Register '{0}' uses the legacy line format which cannot store entry options, run the migrate command first")]
+    LegacyRegisterOptions(PathBuf),
+    #[error(
+        "'{file}' would be stored at the same location within the source control as '{existing}'"
+    )]
+    RepoPathCollision { file: PathBuf, existing: PathBuf },
+    #[error("{0} entries could not be synced")]
+    SyncFailed(usize),
+    #[error("'{0}' has changed both on the filesystem and within the source control since it was last synced, sync it with an explicit direction")]
+    BothSidesChanged(PathBuf),
+    #[error("The encrypted copy of '{0}' has changed within the source control since it was last synced, sync it towards the filesystem first")]
+    EncryptedCopyChanged(PathBuf),
+    #[error("A passphrase is required to protect the key")]
+    PassphraseRequired,
+    #[error("The passphrases do not match")]
+    PassphraseMismatch,
+    #[error("Incorrect passphrase for key '{0}'")]
+    IncorrectPassphrase(PathBuf),
+    #[error("Askpass program '{0}' failed: {1}")]
+    AskpassFailed(String, String),
+    #[error("Nothing to sync, no entry is active on this host with the given tags")]
+    NothingToSync,
+    #[error("There are no secrets or copied files to watch")]
+    NothingToWatch,
+    #[error("Failed watching '{0}' for changes: {1}")]
+    FailedWatching(PathBuf, notify_debouncer_mini::notify::Error),
+    #[error("Some entries are out of sync")]
+    OutOfSync,
+    #[error("Option '{0}' of the configuration file is invalid: {1}")]
+    InvalidOption(String, String),
+    #[error("State file '{0}' is invalid: {1}")]
+    InvalidState(PathBuf, String),
+    #[error("Backup manifest '{0}' is invalid: {1}")]
+    InvalidManifest(PathBuf, String),
+    #[error("Backup '{0}' does not exist")]
+    BackupNotFound(String),
+    #[error("Action is required for the program to function")]
+    ActionRequired,
+    #[error("'{0}' is not an action of '{1}'")]
+    UnsupportedAction(String, String),
+    #[error("Backup id is required to restore a backup")]
+    BackupIdRequired,
+    #[error("Pruning backups requires --older-than or --keep")]
+    PruneCriteriaRequired,
+    #[error("Ignore file '{0}' is invalid: {1}")]
+    InvalidIgnoreFile(PathBuf, ignore::Error),
+    #[error("Only files can be deployed as a copy: '{0}'")]
+    CopyModeDirectory(PathBuf),
+    #[error("Expected a directory at '{0}'")]
+    NotADirectory(PathBuf),
     #[error("Failed checking if file exist")]
     FailedCheckingExistanceOfFile(std::io::Error),
 }
+
+/// Exit code of `status` when an entry is out of sync.
+pub(crate) const EXIT_OUT_OF_SYNC: u8 = 1;
+/// Exit code of invalid or missing arguments, the same code clap exits with.
+pub(crate) const EXIT_USAGE: u8 = 2;
+pub(crate) const EXIT_CONFIG: u8 = 3;
+pub(crate) const EXIT_IO: u8 = 4;
+pub(crate) const EXIT_CRYPTO: u8 = 5;
+/// Exit code of a sync where some entries failed while the others were synced.
+pub(crate) const EXIT_PARTIAL_SYNC: u8 = 6;
+pub(crate) const EXIT_NOTHING_TO_DO: u8 = 7;
+
+/// Exit codes as listed at the end of `--help`.
+pub(crate) const EXIT_CODES_HELP: &str = "Exit codes:
+  0  Success
+  1  Status found entries which are out of sync
+  2  Invalid or missing arguments
+  3  Invalid configuration, register, state or backup
+  4  Reading or writing files failed
+  5  Encryption, decryption or key handling failed
+  6  Some entries of the sync failed
+  7  Nothing to sync or watch, no entry is active on this host with the given tags
+     (a sync whose entries were all skipped, e.g. for being in sync already, succeeds)";
+
+impl ApplicationError {
+    /// Exit code of the process for the class of the error.
+    pub(crate) fn exit_code(&self) -> u8 {
+        use ApplicationError::*;
+        match self {
+            OutOfSync => EXIT_OUT_OF_SYNC,
+            FileInputRequired
+            | FileTypeRequired
+            | SyncDirectionRequired
+            | ActionRequired
+            | UnsupportedAction(..)
+            | BackupIdRequired
+            | RecipientIdRequired
+            | PruneCriteriaRequired => EXIT_USAGE,
+            ConfigFileReadError(_)
+            | FileInRoot(_)
+            | SecretKeyRequired
+            | ErrorExpandingVariable(_)
+            | UndedfinedHomeVariable
+            | InvalidPermissions(_)
+            | InvalidOwner(_)
+            | InvalidRegister(..)
+            | LegacyRegisterOptions(_)
+            | RepoPathCollision { .. }
+            | InvalidOption(..)
+            | InvalidState(..)
+            | InvalidManifest(..)
+            | BackupNotFound(_)
+            | RecipientNotFound(_)
+            | AmbiguousRecipient(_)
+            | InvalidIgnoreFile(..)
+            | CopyModeDirectory(_) => EXIT_CONFIG,
+            FileNotFound(_)
+            | UntrackedSymlinkedFile(_)
+            | FailedRenamingFile { .. }
+            | CouldNotOpenFile(..)
+            | FailedWritingToFile(..)
+            | PathConversionError(_)
+            | ErrorReadingFile(_)
+            | CouldNotCreateDirectories(..)
+            | FailedRemovingFile(..)
+            | InterruptedSync(_)
+            | CorruptJournal(..)
+            | FailedReadingInput(_)
+            | FailedSettingPermissions(..)
+            | FailedSettingOwner(..)
+            | NotADirectory(_)
+            | FailedWatching(..)
+            | FailedCheckingExistanceOfFile(_) => EXIT_IO,
+            PGPKeySignError(_)
+            | KeyGenerationFailed(_)
+            | FailedEncryptingContent(..)
+            | FailedReadingKey(..)
+            | KeyCannotEncrypt(_)
+            | InvalidRecipient(..)
+            | ReencryptionVerificationFailed(_)
+            | PGPMessageReadError(..)
+            | PGPWriterError(..)
+            | FailedDecryptingContent(_)
+            | FailedDecryptingMessageInContent(_)
+            | ErrorReadingContentInMessage(_)
+            | NoContentInPGPMessage
+            | PassphraseRequired
+            | PassphraseMismatch
+            | IncorrectPassphrase(_)
+            | AskpassFailed(..) => EXIT_CRYPTO,
+            SyncFailed(_) | BothSidesChanged(_) | EncryptedCopyChanged(_) => EXIT_PARTIAL_SYNC,
+            NothingToSync | NothingToWatch => EXIT_NOTHING_TO_DO,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exit_codes_are_documented() {
+        for (err, code) in [
+            (ApplicationError::OutOfSync, EXIT_OUT_OF_SYNC),
+            (ApplicationError::SyncDirectionRequired, EXIT_USAGE),
+            (
+                ApplicationError::InvalidOption("option".into(), "invalid".into()),
+                EXIT_CONFIG,
+            ),
+            (ApplicationError::FileNotFound(PathBuf::new()), EXIT_IO),
+            (ApplicationError::NoContentInPGPMessage, EXIT_CRYPTO),
+            (ApplicationError::SyncFailed(1), EXIT_PARTIAL_SYNC),
+            (ApplicationError::NothingToSync, EXIT_NOTHING_TO_DO),
+        ] {
+            assert_eq!(err.exit_code(), code);
+            assert!(EXIT_CODES_HELP.contains(&format!("\n  {code}  ")));
+        }
+    }
+}
@@ -0,0 +1,359 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, ErrorKind, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    crypto::Key,
+    error::ApplicationError,
+    plan::{EntryPlan, Operation, Summary},
+    prompt,
+};
+
+/// Record of the operations started by a running sync.
+///
+/// Every operation is written to the journal before it is performed, so that a failed or
+/// interrupted sync can be rolled back. The journal is removed once the sync has finished.
+pub(crate) struct Journal {
+    path: PathBuf,
+    file: File,
+}
+
+impl Journal {
+    /// Starts a new journal, failing if a journal of an interrupted sync is still present.
+    pub(crate) fn begin(path: &Path) -> Result<Self, ApplicationError> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .map_err(|err| match err.kind() {
+                ErrorKind::AlreadyExists => ApplicationError::InterruptedSync(path.to_path_buf()),
+                _ => ApplicationError::CouldNotOpenFile(path.to_path_buf(), err),
+            })?;
+        Ok(Journal {
+            path: path.to_path_buf(),
+            file,
+        })
+    }
+
+    pub(crate) fn record(&mut self, operation: &Operation) -> Result<(), ApplicationError> {
+        let line = operation.to_journal_line()?;
+        self.file
+            .write_all(format!("{line}\n").as_bytes())
+            .and_then(|_| self.file.sync_data())
+            .map_err(|err| ApplicationError::FailedWritingToFile(self.path.clone(), err))
+    }
+
+    pub(crate) fn finish(self) -> Result<(), ApplicationError> {
+        fs::remove_file(&self.path)
+            .map_err(|err| ApplicationError::FailedRemovingFile(self.path, err))
+    }
+}
+
+/// Reads the operations of an interrupted sync, if a journal is present.
+pub(crate) fn load(path: &Path) -> Result<Option<Vec<Operation>>, ApplicationError> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(ApplicationError::CouldNotOpenFile(path.to_path_buf(), err)),
+    };
+    let mut operations = Vec::new();
+    for (index, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|_| ApplicationError::ErrorReadingFile(path.to_path_buf()))?;
+        operations.push(Operation::from_journal_line(&line).ok_or(
+            ApplicationError::CorruptJournal(path.to_path_buf(), index + 1),
+        )?);
+    }
+    Ok(Some(operations))
+}
+
+/// Reverts the operations in reverse order of execution.
+pub(crate) fn rollback(operations: &[Operation]) -> Result<(), ApplicationError> {
+    for operation in operations.iter().rev() {
+        operation.undo()?;
+    }
+    Ok(())
+}
+
+/// Executes the operations of a sync, rolling back every completed operation on failure.
+///
+/// The failed operation itself is not undone, since whatever it would revert was already there
+/// before it.
+pub(crate) fn execute(
+    journal_path: &Path,
+    operations: &[Operation],
+    key: Option<&Key>,
+) -> Result<(), ApplicationError> {
+    let mut journal = Journal::begin(journal_path)?;
+    for (index, operation) in operations.iter().enumerate() {
+        if let Err(err) = journal
+            .record(operation)
+            .and_then(|_| operation.execute(key))
+        {
+            eprintln!("Sync failed, rolling back {index} operation(s)");
+            if let Err(rollback_err) = rollback(&operations[..index]) {
+                eprintln!("{rollback_err}");
+                eprintln!(
+                    "Rollback is incomplete, the journal is kept at '{}'",
+                    journal_path.display()
+                );
+                return Err(err);
+            }
+            journal.finish()?;
+            return Err(err);
+        }
+    }
+    journal.finish()
+}
+
+/// Executes the plan of every entry on its own, rolling back only the entry which failed and
+/// continuing with the remaining ones.
+///
+/// Returns the operations which took effect together with the outcome per entry. Nothing else is
+/// executed once a rollback is incomplete, since its journal has to be recovered first.
+pub(crate) fn execute_plans(
+    journal_path: &Path,
+    plans: &[EntryPlan],
+    key: Option<&Key>,
+) -> Result<(Vec<Operation>, Summary), ApplicationError> {
+    let mut executed = Vec::new();
+    let mut summary = Summary::default();
+    for plan in plans {
+        let result = match &plan.operations {
+            Ok(operations) if operations.is_empty() => {
+                summary.skipped += 1;
+                continue;
+            }
+            Ok(operations) => execute(journal_path, operations, key).map(|_| operations),
+            Err(err) => {
+                eprintln!("Failed syncing '{}': {err}", plan.path.display());
+                summary.failed += 1;
+                continue;
+            }
+        };
+        match result {
+            Ok(operations) => {
+                executed.extend(operations.iter().cloned());
+                summary.synced += 1;
+            }
+            Err(err) if journal_path.exists() => return Err(err),
+            Err(err) => {
+                eprintln!("Failed syncing '{}': {err}", plan.path.display());
+                summary.failed += 1;
+            }
+        }
+    }
+    Ok((executed, summary))
+}
+
+/// Offers to roll back a sync which was interrupted before it could finish.
+///
+/// Without a terminal to ask on (`interactive` unset) the journal is kept and the interrupted
+/// sync is reported as an error, so that scripts do not wait for an answer.
+pub(crate) fn recover(journal_path: &Path, interactive: bool) -> Result<(), ApplicationError> {
+    let Some(operations) = load(journal_path)? else {
+        return Ok(());
+    };
+    if !interactive {
+        return Err(ApplicationError::InterruptedSync(
+            journal_path.to_path_buf(),
+        ));
+    }
+    println!("A previous sync was interrupted after starting the following operations:");
+    for operation in &operations {
+        println!("    {operation}");
+    }
+    match prompt("[r]oll back, [d]iscard the journal or [K]eep it for later?\n> ")?.as_str() {
+        "r" | "roll back" => {
+            rollback(&operations)?;
+            fs::remove_file(journal_path).map_err(|err| {
+                ApplicationError::FailedRemovingFile(journal_path.to_path_buf(), err)
+            })?;
+            println!("Rolled back {} operation(s)", operations.len());
+        }
+        "d" | "discard" => fs::remove_file(journal_path)
+            .map_err(|err| ApplicationError::FailedRemovingFile(journal_path.to_path_buf(), err))?,
+        _ => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_fs::TempDir;
+
+    #[test]
+    fn test_journal_round_trip() {
+        let tmp_dir = TempDir::new().unwrap();
+        let journal_path = tmp_dir.join("journal");
+        let operations = vec![
+            Operation::MoveToRepo {
+                from: tmp_dir.join("file"),
+                to: tmp_dir.join("repo").join("file"),
+            },
+            Operation::Symlink {
+                target: tmp_dir.join("repo").join("file"),
+                link: tmp_dir.join("file"),
+            },
+            Operation::Encrypt {
+                from: tmp_dir.join("secret"),
+                to: tmp_dir.join("repo").join("secret"),
+                compare: false,
+            },
+            Operation::Encrypt {
+                from: tmp_dir.join("secret"),
+                to: tmp_dir.join("repo").join("secret"),
+                compare: true,
+            },
+            Operation::SetOwner {
+                path: tmp_dir.join("file"),
+                uid: 1000,
+                gid: Some(100),
+            },
+            Operation::SetOwner {
+                path: tmp_dir.join("file"),
+                uid: 1000,
+                gid: None,
+            },
+        ];
+
+        let mut journal = Journal::begin(&journal_path).unwrap();
+        for operation in &operations {
+            journal.record(operation).unwrap();
+        }
+        assert!(matches!(
+            Journal::begin(&journal_path),
+            Err(ApplicationError::InterruptedSync(_))
+        ));
+        assert_eq!(load(&journal_path).unwrap(), Some(operations));
+
+        journal.finish().unwrap();
+        assert_eq!(load(&journal_path).unwrap(), None);
+
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_recover_without_terminal() {
+        let tmp_dir = TempDir::new().unwrap();
+        let journal_path = tmp_dir.join("journal");
+        recover(&journal_path, false).unwrap();
+
+        let mut journal = Journal::begin(&journal_path).unwrap();
+        journal
+            .record(&Operation::Symlink {
+                target: tmp_dir.join("repo").join("file"),
+                link: tmp_dir.join("file"),
+            })
+            .unwrap();
+        assert!(matches!(
+            recover(&journal_path, false),
+            Err(ApplicationError::InterruptedSync(_))
+        ));
+        assert!(journal_path.exists());
+
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_failed_sync_is_rolled_back() {
+        let tmp_dir = TempDir::new().unwrap();
+        let journal_path = tmp_dir.join("journal");
+        let file = tmp_dir.join("file");
+        let dotfile = tmp_dir.join("repo").join("file");
+        fs::write(&file, "content").unwrap();
+        let operations = vec![
+            Operation::MoveToRepo {
+                from: file.clone(),
+                to: dotfile.clone(),
+            },
+            Operation::Symlink {
+                target: dotfile.clone(),
+                link: file.clone(),
+            },
+            Operation::Backup {
+                from: tmp_dir.join("missing"),
+                to: tmp_dir.join("missing.bkp"),
+            },
+        ];
+
+        assert!(execute(&journal_path, &operations, None).is_err());
+        assert!(!file.is_symlink());
+        assert_eq!(fs::read_to_string(&file).unwrap(), "content");
+        assert!(!dotfile.exists());
+        assert!(!journal_path.exists());
+
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_failed_operation_is_not_undone() {
+        let tmp_dir = TempDir::new().unwrap();
+        let journal_path = tmp_dir.join("journal");
+        let existing = tmp_dir.join("existing");
+        fs::write(&existing, "existing").unwrap();
+        let operations = vec![Operation::Backup {
+            from: tmp_dir.join("missing"),
+            to: existing.clone(),
+        }];
+
+        assert!(execute(&journal_path, &operations, None).is_err());
+        assert_eq!(fs::read_to_string(&existing).unwrap(), "existing");
+        assert!(!tmp_dir.join("missing").exists());
+        assert!(!journal_path.exists());
+
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_failed_entry_does_not_stop_others() {
+        let tmp_dir = TempDir::new().unwrap();
+        let journal_path = tmp_dir.join("journal");
+        let file = tmp_dir.join("file");
+        let dotfile = tmp_dir.join("repo").join("file");
+        fs::write(&file, "content").unwrap();
+        let plans = vec![
+            EntryPlan {
+                path: tmp_dir.join("missing"),
+                operations: Ok(vec![Operation::MoveToRepo {
+                    from: tmp_dir.join("missing"),
+                    to: tmp_dir.join("repo").join("missing"),
+                }]),
+            },
+            EntryPlan {
+                path: tmp_dir.join("foreign"),
+                operations: Err(ApplicationError::UntrackedSymlinkedFile(
+                    tmp_dir.join("foreign"),
+                )),
+            },
+            EntryPlan {
+                path: tmp_dir.join("linked"),
+                operations: Ok(Vec::new()),
+            },
+            EntryPlan {
+                path: file.clone(),
+                operations: Ok(vec![Operation::MoveToRepo {
+                    from: file.clone(),
+                    to: dotfile.clone(),
+                }]),
+            },
+        ];
+
+        let (executed, summary) = execute_plans(&journal_path, &plans, None).unwrap();
+        assert_eq!(
+            summary,
+            Summary {
+                synced: 1,
+                skipped: 1,
+                failed: 2,
+            }
+        );
+        assert_eq!(executed, plans[3].operations.as_ref().unwrap().clone());
+        assert_eq!(fs::read_to_string(&dotfile).unwrap(), "content");
+        assert!(!journal_path.exists());
+
+        tmp_dir.close().unwrap();
+    }
+}
@@ -0,0 +1,320 @@
+use std::{
+    fs,
+    io::ErrorKind,
+    path::{Component, Path, PathBuf},
+};
+
+use crate::{
+    create_missing_parents, dotfile_path,
+    error::ApplicationError,
+    legacy_dotfile_path,
+    register::{normalize_path, Entry, Register},
+};
+
+/// Whether two tracked files claim the same location within the source control.
+///
+/// Nested locations are only allowed when the files on the filesystem are nested the same way,
+/// e.g. a file tracked inside of a tracked directory.
+fn collides(file: &Path, dotfile: &Path, other_file: &Path, other_dotfile: &Path) -> bool {
+    if file == other_file {
+        return false;
+    }
+    if let Ok(relative) = dotfile.strip_prefix(other_dotfile) {
+        return other_file.join(relative) != file;
+    }
+    if let Ok(relative) = other_dotfile.strip_prefix(dotfile) {
+        return file.join(relative) != other_file;
+    }
+    false
+}
+
+/// Finds an entry of the register whose location within `base_directory` collides with `entry`.
+pub(crate) fn find_collision<'a>(
+    register: &'a Register,
+    entry: &Entry,
+    base_directory: &Path,
+) -> Result<Option<&'a Entry>, ApplicationError> {
+    let dotfile = entry.dotfile_path(base_directory.to_path_buf())?;
+    for other in &register.entries {
+        let other_dotfile = other.dotfile_path(base_directory.to_path_buf())?;
+        if collides(&entry.path, &dotfile, &other.path, &other_dotfile) {
+            return Ok(Some(other));
+        }
+    }
+    Ok(None)
+}
+
+/// Path leading from the directory containing `link` to `target`, both being absolute.
+pub(crate) fn relative_target(link: &Path, target: &Path) -> PathBuf {
+    let directory = normalize_path(link.parent().unwrap_or(link));
+    let target = normalize_path(target);
+    let common = directory
+        .components()
+        .zip(target.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+    directory
+        .components()
+        .skip(common)
+        .map(|_| Component::ParentDir)
+        .chain(target.components().skip(common))
+        .collect()
+}
+
+/// Whether `link` is a symlink pointing to `target`, by an absolute or a relative path.
+pub(crate) fn links_to(link: &Path, target: &Path) -> bool {
+    let Ok(link_target) = fs::read_link(link) else {
+        return false;
+    };
+    let resolved = match link_target.is_relative() {
+        true => link.parent().unwrap_or(link).join(link_target),
+        false => link_target,
+    };
+    normalize_path(&resolved) == normalize_path(target)
+}
+
+/// Whether `link` leads to `target`, either directly or through symlinked directories such as a
+/// source control which is itself reached through a symlink.
+pub(crate) fn resolves_to(link: &Path, target: &Path) -> bool {
+    links_to(link, target)
+        || matches!(
+            (fs::canonicalize(link), fs::canonicalize(target)),
+            (Ok(a), Ok(b)) if a == b
+        )
+}
+
+/// Removes `directory` and every directory below it which does not contain any file.
+pub(crate) fn prune_empty_dirs(directory: &Path) -> Result<(), ApplicationError> {
+    if !fs::symlink_metadata(directory).is_ok_and(|metadata| metadata.is_dir()) {
+        return Ok(());
+    }
+    for entry in fs::read_dir(directory)
+        .map_err(|err| ApplicationError::CouldNotOpenFile(directory.to_path_buf(), err))?
+    {
+        let entry = entry
+            .map_err(|err| ApplicationError::CouldNotOpenFile(directory.to_path_buf(), err))?;
+        prune_empty_dirs(&entry.path())?;
+    }
+    let is_empty = fs::read_dir(directory)
+        .map_err(|err| ApplicationError::CouldNotOpenFile(directory.to_path_buf(), err))?
+        .next()
+        .is_none();
+    if is_empty {
+        fs::remove_dir(directory)
+            .map_err(|err| ApplicationError::FailedRemovingFile(directory.to_path_buf(), err))?;
+    }
+    Ok(())
+}
+
+/// Removes every empty directory containing `path` within `base_directory`, stopping at the first
+/// one which still contains anything, so no empty directories mirroring the filesystem are left
+/// behind.
+pub(crate) fn prune_empty_parents(
+    path: &Path,
+    base_directory: &Path,
+) -> Result<(), ApplicationError> {
+    for directory in path.ancestors().skip(1).take_while(|directory| {
+        directory.starts_with(base_directory) && *directory != base_directory
+    }) {
+        let is_empty = match fs::read_dir(directory) {
+            Ok(mut entries) => entries.next().is_none(),
+            Err(err) if err.kind() == ErrorKind::NotFound => continue,
+            Err(err) => {
+                return Err(ApplicationError::CouldNotOpenFile(
+                    directory.to_path_buf(),
+                    err,
+                ))
+            }
+        };
+        if !is_empty {
+            break;
+        }
+        fs::remove_dir(directory)
+            .map_err(|err| ApplicationError::FailedRemovingFile(directory.to_path_buf(), err))?;
+    }
+    Ok(())
+}
+
+/// Moves files stored with the layout of earlier versions to their location mirroring the
+/// filesystem, re-pointing symlinks on the filesystem to the new location.
+///
+/// Returns the number of moved files.
+pub(crate) fn migrate_layout(
+    register: &Register,
+    base_directory: &Path,
+) -> Result<usize, ApplicationError> {
+    let mut moved = 0;
+    for entry in register
+        .entries
+        .iter()
+        .filter(|entry| entry.repo_path.is_none())
+    {
+        let old = legacy_dotfile_path(base_directory.to_path_buf(), &entry.path)?;
+        let new = dotfile_path(base_directory.to_path_buf(), &entry.path)?;
+        if old == new || fs::symlink_metadata(&old).is_err() {
+            continue;
+        }
+        if fs::symlink_metadata(&new).is_ok() {
+            eprintln!(
+                "'{}' already exists, '{}' is left in place",
+                new.display(),
+                old.display()
+            );
+            continue;
+        }
+
+        create_missing_parents(&new)?;
+        fs::rename(&old, &new).map_err(|err| ApplicationError::FailedRenamingFile {
+            err,
+            from: old.clone(),
+            to: new.clone(),
+        })?;
+        if links_to(&entry.path, &old) {
+            let relative = fs::read_link(&entry.path).is_ok_and(|target| target.is_relative());
+            let target = match relative {
+                true => relative_target(&entry.path, &new),
+                false => new.clone(),
+            };
+            fs::remove_file(&entry.path)
+                .map_err(|err| ApplicationError::FailedRemovingFile(entry.path.clone(), err))?;
+            std::os::unix::fs::symlink(&target, &entry.path).map_err(|err| {
+                ApplicationError::FailedRenamingFile {
+                    err,
+                    from: new.clone(),
+                    to: entry.path.clone(),
+                }
+            })?;
+        }
+        moved += 1;
+    }
+    Ok(moved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::register::RegisterFormat;
+
+    #[test]
+    fn test_collisions() {
+        let repo = Path::new("/repo");
+        let nvim = Path::new("/home/me/.config/nvim");
+        let init = Path::new("/home/me/.config/nvim/init.lua");
+        let vimrc = Path::new("/home/me/.vimrc");
+
+        assert!(!collides(
+            init,
+            &repo.join("nvim/init.lua"),
+            nvim,
+            &repo.join("nvim")
+        ));
+        assert!(collides(
+            vimrc,
+            &repo.join("nvim/init.lua"),
+            nvim,
+            &repo.join("nvim")
+        ));
+        assert!(collides(
+            vimrc,
+            &repo.join("config"),
+            nvim,
+            &repo.join("config")
+        ));
+        assert!(!collides(
+            vimrc,
+            &repo.join("vimrc"),
+            nvim,
+            &repo.join("nvim")
+        ));
+    }
+
+    #[test]
+    fn test_relative_links() {
+        let tmp_dir = assert_fs::TempDir::new().unwrap();
+        let link = tmp_dir.join("home").join(".config").join("app");
+        let target = tmp_dir
+            .join("repo")
+            .join("home")
+            .join(".config")
+            .join("app");
+        assert_eq!(
+            relative_target(&link, &target),
+            PathBuf::from("../../repo/home/.config/app")
+        );
+
+        fs::create_dir_all(link.parent().unwrap()).unwrap();
+        std::os::unix::fs::symlink(relative_target(&link, &target), &link).unwrap();
+        assert!(links_to(&link, &target));
+        assert!(!links_to(&link, &tmp_dir.join("elsewhere")));
+
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_find_collision_with_repo_path() {
+        let home = PathBuf::from(std::env::var("HOME").unwrap());
+        let base_directory = Path::new("/repo/symlinks");
+        let register = Register {
+            entries: vec![Entry {
+                repo_path: Some(PathBuf::from("home/.config/foo/config")),
+                ..Entry::new(home.join(".local").join("foo").join("config"))
+            }],
+            format: RegisterFormat::Structured,
+        };
+
+        let colliding = Entry::new(home.join(".config").join("foo").join("config"));
+        assert_eq!(
+            find_collision(&register, &colliding, base_directory).unwrap(),
+            Some(&register.entries[0])
+        );
+        let unrelated = Entry::new(home.join(".vimrc"));
+        assert_eq!(
+            find_collision(&register, &unrelated, base_directory).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_migrate_layout() {
+        let tmp_dir = assert_fs::TempDir::new().unwrap();
+        let home = PathBuf::from(std::env::var("HOME").unwrap());
+        let base_directory = tmp_dir.join("symlinks");
+        let entry = Entry::new(home.join(".dotfiles-test-layout"));
+        let old = legacy_dotfile_path(base_directory.clone(), &entry.path).unwrap();
+        create_missing_parents(&old).unwrap();
+        fs::write(&old, "content").unwrap();
+        let register = Register {
+            entries: vec![entry.clone()],
+            format: RegisterFormat::Structured,
+        };
+
+        assert_eq!(migrate_layout(&register, &base_directory).unwrap(), 1);
+        let new = dotfile_path(base_directory.clone(), &entry.path).unwrap();
+        assert!(!old.exists());
+        assert_eq!(fs::read_to_string(new).unwrap(), "content");
+        assert_eq!(migrate_layout(&register, &base_directory).unwrap(), 0);
+
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_prune_empty_parents() {
+        let tmp_dir = assert_fs::TempDir::new().unwrap();
+        let base_directory = tmp_dir.join("symlinks");
+        let dotfile = base_directory.join("home").join(".config").join("app");
+        fs::create_dir_all(&dotfile).unwrap();
+        fs::write(base_directory.join("home").join(".bashrc"), "content").unwrap();
+
+        prune_empty_dirs(&dotfile).unwrap();
+        prune_empty_parents(&dotfile, &base_directory).unwrap();
+        assert!(!base_directory.join("home").join(".config").exists());
+        assert!(base_directory.join("home").join(".bashrc").exists());
+
+        fs::remove_file(base_directory.join("home").join(".bashrc")).unwrap();
+        prune_empty_parents(&dotfile, &base_directory).unwrap();
+        assert!(!base_directory.join("home").exists());
+        assert!(base_directory.is_dir());
+
+        tmp_dir.close().unwrap();
+    }
+}
@@ -1,25 +1,44 @@
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use configparser::ini::Ini;
-use pgp::types::SecretKeyTrait;
-use pgp::{Deserializable, Message, SignedSecretKey};
-use rand::RngCore;
-use std::io::{prelude::*, BufWriter};
+use std::io::{prelude::*, IsTerminal};
 use std::ops::Deref;
 use std::{
-    fs::{self, File, OpenOptions},
-    io::{BufRead, BufReader},
-    path::PathBuf,
+    fs,
+    path::{Path, PathBuf},
+    process::ExitCode,
 };
 
-use cli::{Cli, FileType, PrimaryAction, SyncDirection};
+use backup::BackupSet;
+use cli::{Action, Cli, FileType, PrimaryAction, SyncDirection};
+use conflict::{ConflictStrategy, Resolver};
+use dotignore::DotIgnore;
 use error::ApplicationError;
+use register::{Entry, LinkMode, Register};
+use state::State;
 
+mod backup;
 mod cli;
+mod conflict;
+mod crypto;
+mod dotignore;
 mod error;
-
-pub(crate) fn main() {
-    if let Err(err) = inner_main() {
-        eprintln!("{}", err)
+mod journal;
+mod layout;
+mod plan;
+mod recipients;
+mod register;
+mod rotate;
+mod state;
+mod status;
+mod watch;
+
+pub(crate) fn main() -> ExitCode {
+    match inner_main() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("{}", err);
+            ExitCode::from(err.exit_code())
+        }
     }
 }
 
@@ -38,7 +57,7 @@ pub(crate) fn inner_main() -> Result<(), ApplicationError> {
     for dir in configuration_directories {
         if !dir
             .try_exists()
-            .map_err(|err| ApplicationError::FailedCheckingExistanceOfFile(err))?
+            .map_err(ApplicationError::FailedCheckingExistanceOfFile)?
         {
             continue;
         }
@@ -54,7 +73,7 @@ pub(crate) fn inner_main() -> Result<(), ApplicationError> {
         None => match config.get("options", "source_control_folder") {
             Some(dotfiles_dir) => PathBuf::from(
                 shellexpand::full(&dotfiles_dir)
-                    .map_err(|err| ApplicationError::ErrorExpandingVariable(err))?
+                    .map_err(ApplicationError::ErrorExpandingVariable)?
                     .deref(),
             ),
             None => PathBuf::from(std::env::var("HOME").unwrap()).join(".dotfiles"),
@@ -65,235 +84,143 @@ pub(crate) fn inner_main() -> Result<(), ApplicationError> {
      * folders to be tracked and symlinked */
     let symlinks_cfg_path = dotfiles_dir.join("cfg").join("symlinks");
     let secrets_cfg_path = dotfiles_dir.join("cfg").join("secrets");
+    let recipients_dir = dotfiles_dir.join("cfg").join("recipients");
+    let journal_path = dotfiles_dir.join(".sync-journal");
+
+    /* Only commands changing files have to wait for an interrupted sync to be recovered */
+    if matches!(
+        (&options.primary_action, &options.action),
+        (
+            PrimaryAction::Sync
+                | PrimaryAction::Remove
+                | PrimaryAction::Watch
+                | PrimaryAction::RotateKey,
+            _
+        ) | (PrimaryAction::Backups, Some(Action::Restore))
+            | (
+                PrimaryAction::Recipients,
+                Some(Action::Add | Action::Remove)
+            )
+    ) {
+        journal::recover(&journal_path, std::io::stdin().is_terminal())?;
+    }
 
     match &options.primary_action {
         PrimaryAction::Sync => {
-            let sync_direction = &options.sync_direction;
-            match File::open(&symlinks_cfg_path) {
-                Ok(symlinks_cfg_file) => {
-                    let symlink_reader = BufReader::new(symlinks_cfg_file);
-                    for line in symlink_reader.lines() {
-                        let line = line.as_ref().map_err(|_| {
-                            ApplicationError::ConfigFileReadError(symlinks_cfg_path.clone())
-                        })?;
-                        /* A tracked file contain two locations, one for the symlink and one for the real
-                         * file */
-                        let file = PathBuf::from(line);
-                        let dotfile_path = dotfile_path(dotfiles_dir.join("symlinks"), &file)?;
-                        if file.is_symlink() {
-                            if dotfile_path.try_exists().map_err(|err| {
-                                ApplicationError::FailedCheckingExistanceOfFile(err)
-                            })? {
-                                println!(
-                                    "'{}' already tracked",
-                                    file.into_os_string().into_string().unwrap()
-                                );
-                                continue;
-                            }
-                        }
-
-                        create_missing_parents(&dotfile_path)?;
-
-                        match sync_direction {
-                            SyncDirection::Dotfiles => {
-                                if file.is_symlink() {
-                                    return Err(ApplicationError::UntrackedSymlinkedFile(
-                                        file.clone(),
-                                    ));
-                                }
-                                if !file.try_exists().map_err(|err| {
-                                    ApplicationError::FailedCheckingExistanceOfFile(err)
-                                })? {
-                                    eprintln!("{}", ApplicationError::FileNotFound(file));
-                                    continue;
-                                }
-                                std::fs::rename(&file, &dotfile_path).map_err(|err| {
-                                    ApplicationError::FailedRenamingFile {
-                                        err,
-                                        from: file.clone(),
-                                        to: dotfile_path.clone(),
-                                    }
-                                })?;
-                            }
-                            SyncDirection::Filesystem => {
-                                if file.try_exists().map_err(|err| {
-                                    ApplicationError::FailedCheckingExistanceOfFile(err)
-                                })? {
-                                    let bkp_file = bkp_file(&file)?;
-                                    std::fs::rename(&file, &bkp_file).map_err(|err| {
-                                        ApplicationError::FailedRenamingFile {
-                                            err,
-                                            from: file.clone(),
-                                            to: bkp_file,
-                                        }
-                                    })?;
-                                } else {
-                                    continue;
-                                }
-                            }
-                        }
-
-                        std::os::unix::fs::symlink(&dotfile_path, &file).map_err(|err| {
-                            ApplicationError::FailedRenamingFile {
-                                err,
-                                from: dotfile_path.to_path_buf(),
-                                to: file.clone(),
-                            }
-                        })?;
-                    }
-                }
-                Err(err) => eprintln!("{err}"),
+            let sync_direction = options
+                .sync_direction
+                .as_ref()
+                .ok_or(ApplicationError::SyncDirectionRequired)?;
+            let backups = BackupSet::new(&backup::store_dir()?);
+            let resolver = Resolver {
+                strategy: match options.yes {
+                    true => ConflictStrategy::TakeRepo,
+                    false => options.strategy,
+                },
+                dry_run: options.dry_run,
+            };
+            let state_path = state::state_path()?;
+            let mut state = State::load(&state_path)?;
+            let relative_symlinks = flag_option(&config, "relative_symlinks")?;
+            let preserve_owner = flag_option(&config, "preserve_owner")?;
+            let context = plan::Context {
+                backups: &backups,
+                resolver: &resolver,
+                state: &state,
+                relative_symlinks,
             };
+            let symlinks = Register::load(&symlinks_cfg_path)?;
+            let mut plans = plan::plan_symlinks(
+                sync_direction,
+                &dotfiles_dir,
+                &symlinks,
+                &options.tags,
+                &context,
+            )?;
 
             let maybe_key = key_or_cfg(&options.secret_key, config);
+            let mut secrets = Register::load(&secrets_cfg_path)?;
+            if !secrets.entries.is_empty() {
+                match &maybe_key {
+                    Ok(_) => plans.extend(plan::plan_secrets(
+                        sync_direction,
+                        &dotfiles_dir,
+                        &secrets,
+                        &options.tags,
+                        &context,
+                    )),
+                    Err(err) => eprintln!("{err}"),
+                }
+            }
 
-            match File::open(&secrets_cfg_path)
-                .map_err(|err| ApplicationError::CouldNotOpenFile(secrets_cfg_path.clone(), err))
-            {
-                Ok(secrets_cfg_file) => {
-                    match maybe_key {
-                        Ok(key_path) => {
-                            let secrets_reader = BufReader::new(&secrets_cfg_file);
-                            for line in secrets_reader.lines() {
-                                let line = line.as_ref().map_err(|_| {
-                                    ApplicationError::ConfigFileReadError(secrets_cfg_path.clone())
-                                })?;
-
-                                let file_path = PathBuf::from(line);
-
-                                let dotfile_path =
-                                    dotfile_path(dotfiles_dir.join("secrets"), &file_path)?;
-
-                                let key_file = File::open(&key_path).map_err(|err| {
-                                    ApplicationError::CouldNotOpenFile(key_path.clone(), err)
-                                })?;
-                                let key = SignedSecretKey::from_armor_single(key_file)
-                                    .map_err(|err| {
-                                        ApplicationError::FailedReadingKey(key_path.clone(), err)
-                                    })?
-                                    .0;
-
-                                match sync_direction {
-                                    SyncDirection::Dotfiles => {
-                                        let message = Message::new_literal(
-                                            "none",
-                                            fs::read_to_string(&file_path)
-                                                .map_err(|err| {
-                                                    ApplicationError::CouldNotOpenFile(
-                                                        file_path.clone(),
-                                                        err,
-                                                    )
-                                                })?
-                                                .as_str(),
-                                        );
-                                        let encrypted_content = message
-                                            .encrypt_to_keys(
-                                                &mut rand::thread_rng(),
-                                                pgp::crypto::sym::SymmetricKeyAlgorithm::AES128,
-                                                &[&key.public_key()],
-                                            )
-                                            .map_err(|err| {
-                                                ApplicationError::FailedEncryptingContent(
-                                                    file_path.clone(),
-                                                    err,
-                                                )
-                                            })?;
-                                        let mut dotfile = OpenOptions::new()
-                                            .create(true)
-                                            .write(true)
-                                            .open(&dotfile_path)
-                                            .map_err(|err| {
-                                                ApplicationError::CouldNotOpenFile(
-                                                    dotfile_path.clone(),
-                                                    err,
-                                                )
-                                            })?;
-
-                                        encrypted_content
-                                            .to_armored_writer(&mut dotfile, None)
-                                            .map_err(|err| {
-                                                ApplicationError::PGPWriterError(
-                                                    dotfile_path.clone(),
-                                                    err,
-                                                )
-                                            })?;
-                                    }
-                                    SyncDirection::Filesystem => {
-                                        let dotfile = File::open(&dotfile_path).map_err(|err| {
-                                            ApplicationError::CouldNotOpenFile(
-                                                dotfile_path.clone(),
-                                                err,
-                                            )
-                                        })?;
-                                        let (message, _) = Message::from_armor_single(dotfile)
-                                            .map_err(|err| {
-                                                ApplicationError::PGPMessageReadError(
-                                                    dotfile_path.clone(),
-                                                    err,
-                                                )
-                                            })?;
-                                        /* let password = rpassword::prompt_password(
-                                            "Please input password to unlock the key\n> ",
-                                        )
-                                        .map_err(|_| ApplicationError::PasswordRequired)?; */
-
-                                        let (decryptor, _) = message
-                                            .decrypt(|| String::new(), &[&key])
-                                            .map_err(|_| {
-                                                ApplicationError::FailedDecryptingContent(
-                                                    dotfile_path.clone(),
-                                                )
-                                            })?;
-
-                                        for msg in decryptor {
-                                            let bytes = msg.map_err(|err| ApplicationError::FailedDecryptingMessageInContent(err))?
-                                                    .get_content().map_err(|err| ApplicationError::ErrorReadingContentInMessage(err))?
-                                                    .ok_or(ApplicationError::NoContentInPGPMessage)?;
-
-                                            let clear = String::from_utf8(bytes).map_err(|_| {
-                                                ApplicationError::MessageNotUTF8Encoded
-                                            })?;
-                                            if clear.len() > 0 {
-                                                let bkp_file = bkp_file(&file_path)?;
-                                                if file_path.try_exists().map_err(|err| {
-                                                    ApplicationError::FailedCheckingExistanceOfFile(
-                                                        err,
-                                                    )
-                                                })? {
-                                                    fs::rename(&file_path, &bkp_file).map_err(
-                                                        |err| {
-                                                            ApplicationError::FailedRenamingFile {
-                                                                err,
-                                                                from: file_path.clone(),
-                                                                to: bkp_file.clone(),
-                                                            }
-                                                        },
-                                                    )?;
-                                                }
-                                                fs::write(&file_path, &clear).map_err(|err| {
-                                                    ApplicationError::FailedWritingToFile(
-                                                        file_path.clone(),
-                                                        err,
-                                                    )
-                                                })?;
-                                                break;
-                                            }
-                                        }
-                                    }
-                                };
+            /* Entries skipped for being in sync are a successful sync, only a run which could
+             * not have synced anything is reported */
+            if plans.is_empty() {
+                return Err(ApplicationError::NothingToSync);
+            }
+
+            if options.dry_run {
+                for plan in &plans {
+                    match &plan.operations {
+                        Ok(operations) => {
+                            for operation in operations {
+                                println!("{operation}");
                             }
                         }
-                        Err(err) => eprintln!("{err}"),
+                        Err(err) => eprintln!("Cannot sync '{}': {err}", plan.path.display()),
                     }
                 }
-                Err(err) => eprintln!("{err}"),
+                return Ok(());
+            }
+
+            let mut planned = plans
+                .iter()
+                .filter_map(|plan| plan.operations.as_ref().ok())
+                .flatten();
+            let key = if planned.clone().any(plan::Operation::requires_key) {
+                Some(crypto::load_key(&maybe_key?, &recipients_dir)?)
+            } else {
+                None
             };
+            /* A wrong passphrase fails every secret alike, so it is checked before syncing */
+            if let Some(key) = &key {
+                if planned.any(plan::Operation::requires_passphrase) {
+                    key.unlock()?;
+                }
+            }
+            let (executed, summary) = journal::execute_plans(&journal_path, &plans, key.as_ref())?;
+            let operation = match sync_direction {
+                SyncDirection::Dotfiles => "sync --sync-direction dotfiles",
+                SyncDirection::Filesystem => "sync --sync-direction filesystem",
+                SyncDirection::Auto => "sync --sync-direction auto",
+            };
+            backups.write_manifest(&executed, operation)?;
+            let base_directory = dotfiles_dir.join("symlinks");
+            let copies_changed = state.record_copies(&symlinks, &base_directory, &options.tags)?;
+            let links_changed = state.record_links(
+                &symlinks,
+                &base_directory,
+                &options.tags,
+                &DotIgnore::load(&dotfiles_dir)?,
+            )?;
+            if copies_changed | links_changed | state.record_secrets(&executed)? {
+                state.save(&state_path)?;
+            }
+            if secrets.record_permissions(&executed, preserve_owner)? {
+                secrets.save(&secrets_cfg_path)?;
+            }
+            println!("Sync finished: {summary}");
+            if summary.failed > 0 {
+                return Err(ApplicationError::SyncFailed(summary.failed));
+            }
         }
         PrimaryAction::Add => {
-            // TODO: Implement fix for edge case where file already is added to configuration
-            let cfg_file_path = match &options.file_type {
-                FileType::Config => symlinks_cfg_path,
-                FileType::Secret => secrets_cfg_path,
+            let file_type = options
+                .file_type
+                .ok_or(ApplicationError::FileTypeRequired)?;
+            let (cfg_file_path, base_directory) = match file_type {
+                FileType::Config => (symlinks_cfg_path, dotfiles_dir.join("symlinks")),
+                FileType::Secret => (secrets_cfg_path, dotfiles_dir.join("secrets")),
             };
             let file = &options.file.ok_or(ApplicationError::FileInputRequired)?;
             let abs_path = expand_variables_in_path(file)?;
@@ -303,87 +230,128 @@ pub(crate) fn inner_main() -> Result<(), ApplicationError> {
 
             create_missing_parents(&cfg_file_path)?;
 
-            {
-                let cfg_file = OpenOptions::new()
-                    .create(true)
-                    .read(true)
-                    .append(true)
-                    .open(&cfg_file_path)
-                    .map_err(|err| {
-                        ApplicationError::CouldNotOpenFile(cfg_file_path.clone(), err)
-                    })?;
-                let reader = BufReader::new(&cfg_file);
-                for line in reader.lines() {
-                    let line = line
-                        .as_ref()
-                        .map_err(|_| ApplicationError::ErrorReadingFile(cfg_file_path.clone()))?;
-                    if line.contains(&abs_path_str) {
-                        println!("'{}' is already tracked", abs_path_str);
-                        return Ok(());
-                    }
+            if fs::symlink_metadata(&abs_path).is_err() {
+                return Err(ApplicationError::FileNotFound(abs_path));
+            }
+
+            let mut register = Register::load(&cfg_file_path)?;
+            if register.position(&abs_path).is_some() {
+                println!("'{}' is already tracked", abs_path_str);
+                return Ok(());
+            }
+            for other in register.overlapping(&abs_path) {
+                match other.path.starts_with(&abs_path) {
+                    true => eprintln!(
+                        "Warning: '{}' contains the tracked '{}'",
+                        abs_path_str,
+                        other.path.display()
+                    ),
+                    false => eprintln!(
+                        "Warning: '{}' is nested inside of the tracked '{}'",
+                        abs_path_str,
+                        other.path.display()
+                    ),
                 }
             }
+            if matches!(file_type, FileType::Secret) {
+                plan::warn_if_readable(&abs_path)?;
+            }
+            let mut entry = Entry {
+                tags: options.tags.clone(),
+                link_mode: options.link_mode,
+                ..Entry::new(abs_path.clone())
+            };
+            if matches!(file_type, FileType::Config)
+                && fs::symlink_metadata(&abs_path).is_ok_and(|metadata| metadata.is_dir())
             {
-                let mut cfg_file = OpenOptions::new()
-                    .write(true)
-                    .append(true)
-                    .open(&cfg_file_path)
-                    .map_err(|err| {
-                        ApplicationError::CouldNotOpenFile(cfg_file_path.clone(), err)
-                    })?;
-                cfg_file
-                    .write(format!("{}\n", abs_path_str).as_bytes())
-                    .map_err(|err| {
-                        ApplicationError::FailedWritingToFile(cfg_file_path.clone(), err)
-                    })?;
-                println!(
-                    "'{}' has been added to '{}'",
-                    abs_path_str,
-                    cfg_file_path.file_name().unwrap().to_str().unwrap(),
-                );
+                let ignored = DotIgnore::load(&dotfiles_dir)?.walk(&abs_path)?.ignored;
+                for file in &ignored {
+                    println!("Ignoring '{}'", abs_path.join(file).display());
+                }
+                /* Ignored files can only stay in place when the directory itself stays real */
+                if !ignored.is_empty() && entry.link_mode.is_none() {
+                    entry.link_mode = Some(LinkMode::Stow);
+                    println!(
+                        "'{}' is linked file by file to leave ignored files in place",
+                        abs_path_str
+                    );
+                }
+            }
+            if let Some(existing) = layout::find_collision(&register, &entry, &base_directory)? {
+                return Err(ApplicationError::RepoPathCollision {
+                    file: abs_path,
+                    existing: existing.path.clone(),
+                });
             }
+            register.entries.push(entry);
+            register.save(&cfg_file_path)?;
+            println!(
+                "'{}' has been added to '{}'",
+                abs_path_str,
+                cfg_file_path.file_name().unwrap().to_str().unwrap(),
+            );
         }
         PrimaryAction::Remove => {
-            let cfg_file_path = match &options.file_type {
-                FileType::Config => symlinks_cfg_path,
-                FileType::Secret => secrets_cfg_path,
+            let file_type = options
+                .file_type
+                .ok_or(ApplicationError::FileTypeRequired)?;
+            let (cfg_file_path, base_directory) = match file_type {
+                FileType::Config => (symlinks_cfg_path, dotfiles_dir.join("symlinks")),
+                FileType::Secret => (secrets_cfg_path, dotfiles_dir.join("secrets")),
             };
             let file = options.file.ok_or(ApplicationError::FileInputRequired)?;
             let abs_path = expand_variables_in_path(&file)?;
-            let out_path = dotfiles_dir.join("cfg.tmp");
-            {
-                let cfg_file = File::open(&cfg_file_path).map_err(|err| {
-                    ApplicationError::CouldNotOpenFile(cfg_file_path.clone(), err)
-                })?;
-                let cfg_out_file = OpenOptions::new()
-                    .create(true)
-                    .write(true)
-                    .open(&out_path)
-                    .map_err(|err| ApplicationError::CouldNotOpenFile(out_path.clone(), err))?;
-                let cfg_file_reader = BufReader::new(&cfg_file);
-                let mut cfg_file_writer = BufWriter::new(&cfg_out_file);
-                for line in cfg_file_reader.lines() {
-                    let line = line
-                        .as_ref()
-                        .map_err(|_| ApplicationError::ErrorReadingFile(cfg_file_path.clone()))?;
-                    if !line.contains(
-                        abs_path
-                            .to_str()
-                            .ok_or(ApplicationError::PathConversionError(abs_path.clone()))?,
-                    ) {
-                        writeln!(cfg_file_writer, "{}", line).map_err(|err| {
-                            ApplicationError::FailedWritingToFile(cfg_file_path.clone(), err)
-                        })?;
+            let mut register = Register::load(&cfg_file_path)?;
+            let Some(position) = register.position(&abs_path) else {
+                println!("'{}' is not tracked", abs_path.display());
+                return Ok(());
+            };
+
+            /* The file is restored onto the filesystem before the entry is dropped, so a failure
+             * leaves it tracked */
+            if !options.keep_in_repo {
+                let entry = &register.entries[position];
+                let dotfile = entry.dotfile_path(base_directory.clone())?;
+                /* Secrets and copies exist on both sides, the file within the source control is
+                 * removed once the file on the filesystem is in place */
+                let duplicated =
+                    matches!(file_type, FileType::Secret) || entry.link_mode() == LinkMode::Copy;
+                let operations = match file_type {
+                    FileType::Config if duplicated => {
+                        plan::plan_untrack_copy(&entry.path, &dotfile)
                     }
+                    FileType::Config => plan::plan_untrack(&entry.path, &dotfile)?,
+                    FileType::Secret => plan::plan_untrack_secret(entry, &dotfile)?,
+                };
+                let key = if operations.iter().any(plan::Operation::requires_key) {
+                    Some(crypto::load_key(
+                        &key_or_cfg(&options.secret_key, config)?,
+                        &recipients_dir,
+                    )?)
+                } else {
+                    None
+                };
+                journal::execute(&journal_path, &operations, key.as_ref())?;
+                match duplicated {
+                    true => {
+                        if fs::symlink_metadata(&entry.path).is_ok() && dotfile.exists() {
+                            fs::remove_file(&dotfile).map_err(|err| {
+                                ApplicationError::FailedRemovingFile(dotfile.clone(), err)
+                            })?;
+                        }
+                    }
+                    false => layout::prune_empty_dirs(&dotfile)?,
                 }
+                layout::prune_empty_parents(&dotfile, &base_directory)?;
             }
-            fs::rename(&out_path, &cfg_file_path).map_err(|err| {
-                ApplicationError::FailedRenamingFile {
-                    err,
-                    from: out_path,
-                    to: cfg_file_path.to_path_buf(),
-                }
-            })?;
+            /* Hashes left behind would make a file tracked again later look already synced */
+            let state_path = state::state_path()?;
+            let mut state = State::load(&state_path)?;
+            if state.forget(&abs_path) {
+                state.save(&state_path)?;
+            }
+            register.entries.remove(position);
+            register.save(&cfg_file_path)?;
 
             println!(
                 "'{}' has been removed from '{}'",
@@ -393,57 +361,289 @@ pub(crate) fn inner_main() -> Result<(), ApplicationError> {
         }
         PrimaryAction::CreateKey => {
             let key_path = key_or_cfg(&options.secret_key, config)?;
-
-            create_missing_parents(&key_path)?;
-
-            let mut key_file = OpenOptions::new()
-                .write(true)
-                .create_new(true)
-                .open(&key_path)
-                .map_err(|err| ApplicationError::CouldNotOpenFile(key_path.clone(), err))?;
-
-            let key_params = pgp::SecretKeyParamsBuilder::default()
-                .key_type(pgp::KeyType::Rsa(2048))
-                .primary_user_id("".to_string())
-                .can_create_certificates(false)
-                .can_sign(true)
-                .preferred_symmetric_algorithms(
-                    vec![pgp::crypto::sym::SymmetricKeyAlgorithm::AES256].into(),
-                )
-                .preferred_hash_algorithms(vec![pgp::crypto::hash::HashAlgorithm::SHA2_256].into())
-                .preferred_compression_algorithms(
-                    vec![pgp::types::CompressionAlgorithm::ZLIB].into(),
+            let params = key_params(options.key_type, &options.user_id, options.expires_in);
+            let passphrase = crypto::new_passphrase()?;
+            let key = crypto::generate_key(&key_path, passphrase, &params)?;
+            println!("'{}' has been created", key_path.display());
+            print_key_params(&key, &params);
+        }
+        PrimaryAction::RotateKey => {
+            let key_path = key_or_cfg(&options.secret_key, config)?;
+            let old = crypto::load_key(&key_path, &recipients_dir)?;
+            old.unlock()?;
+            let new_path = rotate::new_key_path(&key_path);
+            let params = key_params(options.key_type, &options.user_id, options.expires_in);
+            let mut new = crypto::generate_key(&new_path, crypto::new_passphrase()?, &params)?;
+            new.recipients = crypto::load_recipients(&recipients_dir)?;
+
+            let state_path = state::state_path()?;
+            let mut state = State::load(&state_path)?;
+            let secrets_dir = dotfiles_dir.join("secrets");
+            let rotation = rotate::stage(
+                &Register::load(&secrets_cfg_path)?,
+                &secrets_dir,
+                &dotfiles_dir.join(".rotate-key"),
+                &old,
+                &mut new,
+            );
+            /* Nothing has been replaced unless every secret is staged, the new key is of no use
+             * then */
+            if rotation.is_err() {
+                fs::remove_file(&new_path)
+                    .map_err(|err| ApplicationError::FailedRemovingFile(new_path.clone(), err))?;
+            }
+            /* Secrets may be encrypted to the new key from here on, so it is kept whatever fails */
+            let rotated = rotate::replace(
+                rotation?,
+                &secrets_dir,
+                &recipients_dir,
+                &journal_path,
+                &mut new,
+                &mut state,
+            )
+            .and_then(|reencrypted| {
+                state.save(&state_path)?;
+                Ok((reencrypted, rotate::swap_keys(&key_path, &new_path)?))
+            });
+            let (reencrypted, archived) = rotated.inspect_err(|_| {
+                eprintln!(
+                    "The new key is kept at '{}', if the secrets within '{}' have been replaced archive '{}' and move the new key in its place, otherwise remove the new key before rotating again",
+                    new_path.display(),
+                    secrets_dir.display(),
+                    key_path.display()
                 )
-                .build()
-                .unwrap();
-
-            /* let password =
-            rpassword::prompt_password("Please input a password to sign the PGP key\n> ")
-                .map_err(|_| ApplicationError::PasswordRequired)?; */
-
-            let secret_key = key_params
-                .generate()
-                .map_err(|err| ApplicationError::KeyGenerationFailed(err))?;
-            let signed_secret_key = secret_key
-                .sign(|| String::new())
-                .map_err(|_| ApplicationError::PGPKeySignError(key_path.clone()))?;
-
-            signed_secret_key
-                .to_armored_writer(&mut key_file, None)
-                .map_err(|err| ApplicationError::PGPWriterError(key_path.clone(), err))?;
+            })?;
+            println!(
+                "{reencrypted} secret(s) re-encrypted with the new key '{}', the previous key has been archived to '{}'",
+                key_path.display(),
+                archived.display()
+            );
+            print_key_params(&new, &params);
+        }
+        PrimaryAction::ExportKey => {
+            let key = crypto::load_key(&key_or_cfg(&options.secret_key, config)?, &recipients_dir)?;
+            let armored = key.armored_public_key()?;
+            match options.file {
+                Some(file) => {
+                    let file = expand_variables_in_path(&file)?;
+                    create_missing_parents(&file)?;
+                    fs::write(&file, armored)
+                        .map_err(|err| ApplicationError::FailedWritingToFile(file.clone(), err))?;
+                    println!(
+                        "The public key '{}' has been exported to '{}'",
+                        key.fingerprint(),
+                        file.display()
+                    );
+                }
+                None => print!("{armored}"),
+            }
+        }
+        PrimaryAction::Status => {
+            if !status::status(
+                &dotfiles_dir,
+                &symlinks_cfg_path,
+                &secrets_cfg_path,
+                &options.tags,
+            )? {
+                return Err(ApplicationError::OutOfSync);
+            }
+        }
+        PrimaryAction::Watch => {
+            let watched = watch::Watched::new(
+                &Register::load(&symlinks_cfg_path)?,
+                &Register::load(&secrets_cfg_path)?,
+                &options.tags,
+            );
+            if watched.is_empty() {
+                return Err(ApplicationError::NothingToWatch);
+            }
+            let preserve_owner = flag_option(&config, "preserve_owner")?;
+            let key = match watched.secrets.entries.is_empty() {
+                true => None,
+                false => Some(crypto::load_key(
+                    &key_or_cfg(&options.secret_key, config)?,
+                    &recipients_dir,
+                )?),
+            };
+            /* Asking for the passphrase in the middle of the log of an unattended watch would go
+             * unnoticed, so it is asked for before watching */
+            if let Some(key) = &key {
+                key.unlock()?;
+            }
+            watch::watch(
+                &dotfiles_dir,
+                &watched,
+                key.as_ref(),
+                &journal_path,
+                preserve_owner,
+            )?;
+        }
+        PrimaryAction::Migrate => {
+            for (cfg_path, base_directory) in [
+                (&symlinks_cfg_path, dotfiles_dir.join("symlinks")),
+                (&secrets_cfg_path, dotfiles_dir.join("secrets")),
+            ] {
+                let name = cfg_path.file_name().unwrap().to_str().unwrap();
+                match register::migrate(cfg_path, options.old_home.as_deref())? {
+                    Some(migration) => {
+                        if migration.converted {
+                            println!(
+                                "'{}' has been converted to '{}'",
+                                name,
+                                register::structured_path(cfg_path)
+                                    .file_name()
+                                    .unwrap()
+                                    .to_str()
+                                    .unwrap()
+                            );
+                        }
+                        if let Some(old_home) = &options.old_home {
+                            println!(
+                                "{} entries of '{}' have been moved from '{}' to '~'",
+                                migration.rewritten,
+                                name,
+                                old_home.display()
+                            );
+                        }
+                        let moved =
+                            layout::migrate_layout(&Register::load(cfg_path)?, &base_directory)?;
+                        if moved > 0 {
+                            println!(
+                                "{} files of '{}' have been moved to the new layout",
+                                moved, name
+                            );
+                        }
+                        println!("'{}' has been migrated", name);
+                    }
+                    None => println!("'{}' does not exist", name),
+                }
+            }
+        }
+        PrimaryAction::Backups => {
+            let store = backup::store_dir()?;
+            match options
+                .action
+                .as_ref()
+                .ok_or(ApplicationError::ActionRequired)?
+            {
+                Action::List => {
+                    let sets = backup::list(&store)?;
+                    if sets.is_empty() {
+                        println!("No backups in '{}'", store.display());
+                    }
+                    for (id, manifest) in sets {
+                        println!("{id}");
+                        for backup in manifest.backups {
+                            println!("    {} ({})", backup.original.display(), backup.operation);
+                        }
+                    }
+                }
+                Action::Restore => {
+                    let id = options
+                        .id
+                        .as_deref()
+                        .ok_or(ApplicationError::BackupIdRequired)?;
+                    if let Some(replaced) = backup::restore(&store, id, &journal_path)? {
+                        println!("Replaced files have been backed up to '{replaced}'");
+                    }
+                    println!("'{id}' has been restored");
+                }
+                Action::Prune => {
+                    let older_than = options
+                        .older_than
+                        .map(|days| std::time::Duration::from_secs(days * 24 * 60 * 60));
+                    for id in backup::prune(&store, older_than, options.keep)? {
+                        println!("'{id}' has been removed");
+                    }
+                }
+                action => return Err(unsupported_action(action, &options.primary_action)),
+            }
+        }
+        PrimaryAction::Recipients => {
+            let action = options
+                .action
+                .as_ref()
+                .ok_or(ApplicationError::ActionRequired)?;
+            if let Action::List = action {
+                let recipients = crypto::load_recipients(&recipients_dir)?;
+                if recipients.is_empty() {
+                    println!(
+                        "No recipients in '{}', secrets are only encrypted to the secret key",
+                        recipients_dir.display()
+                    );
+                }
+                for recipient in recipients {
+                    println!(
+                        "{}  {}",
+                        recipient.fingerprint(),
+                        recipient.user_ids().join(", ")
+                    );
+                }
+                return Ok(());
+            }
+            if !matches!(action, Action::Add | Action::Remove) {
+                return Err(unsupported_action(action, &options.primary_action));
+            }
+
+            let mut key =
+                crypto::load_key(&key_or_cfg(&options.secret_key, config)?, &recipients_dir)?;
+            let change = match action {
+                Action::Add => {
+                    let file = options.file.ok_or(ApplicationError::FileInputRequired)?;
+                    let recipient = crypto::load_recipient(&expand_variables_in_path(&file)?)?;
+                    if key
+                        .recipients
+                        .iter()
+                        .any(|other| other.fingerprint() == recipient.fingerprint())
+                    {
+                        println!("'{}' is already a recipient", recipient.fingerprint());
+                        return Ok(());
+                    }
+                    recipients::Change::Add(recipient)
+                }
+                _ => {
+                    let id = options.id.ok_or(ApplicationError::RecipientIdRequired)?;
+                    recipients::Change::Remove(
+                        key.recipients[recipients::find(&key.recipients, &id)?].clone(),
+                    )
+                }
+            };
+            let message = match &change {
+                recipients::Change::Add(recipient) => {
+                    format!("'{}' has been added", recipient.fingerprint())
+                }
+                recipients::Change::Remove(recipient) => {
+                    format!("'{}' has been removed", recipient.fingerprint())
+                }
+            };
+            let state_path = state::state_path()?;
+            let mut state = State::load(&state_path)?;
+            let secrets_dir = dotfiles_dir.join("secrets");
+            /* Every secret is staged before the recipients change, so a key which cannot
+             * decrypt them leaves the recipients untouched */
+            let reencrypted = recipients::stage_change(
+                change,
+                &mut key,
+                &Register::load(&secrets_cfg_path)?,
+                &secrets_dir,
+                &dotfiles_dir.join(".recipients"),
+            )?
+            .apply(&secrets_dir, &recipients_dir, &journal_path, &mut state)?;
+            state.save(&state_path)?;
+            println!("{message}, {reencrypted} secret(s) re-encrypted");
         }
     }
     Ok(())
 }
 
-fn create_missing_parents(key_path: &PathBuf) -> Result<(), ApplicationError> {
+fn create_missing_parents(key_path: &Path) -> Result<(), ApplicationError> {
     let key_parent = key_path
         .parent()
-        .ok_or(ApplicationError::FileInRoot(key_path.clone()))?;
+        .ok_or(ApplicationError::FileInRoot(key_path.to_path_buf()))?;
 
     if key_parent
         .try_exists()
-        .map_err(|err| ApplicationError::FailedCheckingExistanceOfFile(err))?
+        .map_err(ApplicationError::FailedCheckingExistanceOfFile)?
     {
         return Ok(());
     }
@@ -457,14 +657,14 @@ fn create_missing_parents(key_path: &PathBuf) -> Result<(), ApplicationError> {
 
 /// Returns a path derived from a base directory and a file.
 ///
-/// If the file is not placed within the users home directory, then the derived file is prepended by a folder
-/// matching the parent of the file input. Reducing the likeliness of two files having the same
-/// name and overwriting each other.
+/// The derived path mirrors the full location of the file, making it unique for every file.
+/// Files within the users home directory are placed below `home` relative to the home directory,
+/// every other file is placed below `root` with its absolute path.
 ///
 /// # Arguments
 ///
 /// * `base_directory` - PathBuf holding path to root of derived path
-/// * `file` - PathBuf reference holding the location of the file to be derived
+/// * `file` - Path reference holding the location of the file to be derived
 ///
 /// # Examples
 ///
@@ -472,39 +672,120 @@ fn create_missing_parents(key_path: &PathBuf) -> Result<(), ApplicationError> {
 /// // Directory located within the source control
 /// let symlinks_base = ["path", "to", "symlinks"].iter().collect::<PathBuf>();
 /// // File not located within home directory and not within the source control
-/// let real_file = ["path", "to", "real", "file"].iter().collect::<PathBuf>();
+/// let real_file = ["/", "path", "to", "real", "file"].iter().collect::<PathBuf>();
 /// let derived_path = dotfile_path(symlinks_base, &real_file).unwrap();
-/// assert_eq(derived_path, ["path", "to", "symlinks", "real", "file"].iter().collect::<PathBuf>());
+/// assert_eq(derived_path, ["path", "to", "symlinks", "root", "path", "to", "real", "file"].iter().collect::<PathBuf>());
 /// ```
-fn dotfile_path<'a>(
+fn dotfile_path(mut base_directory: PathBuf, file: &Path) -> Result<PathBuf, ApplicationError> {
+    let home_dir =
+        PathBuf::from(std::env::var("HOME").map_err(|_| ApplicationError::UndedfinedHomeVariable)?);
+    let relative = match file.strip_prefix(&home_dir) {
+        Ok(relative) => {
+            base_directory.push("home");
+            relative
+        }
+        Err(_) => {
+            base_directory.push("root");
+            file.strip_prefix("/").unwrap_or(file)
+        }
+    };
+    if relative.as_os_str().is_empty() {
+        return Err(ApplicationError::FileInRoot(file.to_path_buf()));
+    }
+    base_directory.push(relative);
+    Ok(base_directory)
+}
+
+/// Returns the path derived by earlier versions, which only kept the name of the parent directory.
+///
+/// Used to move files within existing source controls into the layout of [`dotfile_path`].
+fn legacy_dotfile_path(
     mut base_directory: PathBuf,
-    file: &'a PathBuf,
+    file: &Path,
 ) -> Result<PathBuf, ApplicationError> {
     let parent = file
         .parent()
-        .ok_or(ApplicationError::FileInRoot(file.clone()))?;
+        .ok_or(ApplicationError::FileInRoot(file.to_path_buf()))?;
     let parent_name = parent
         .file_name()
         .ok_or(ApplicationError::FileNotFound(parent.to_path_buf()))?;
-    if &PathBuf::from(std::env::var("HOME").unwrap()) != parent && !file.is_dir() {
+    if PathBuf::from(std::env::var("HOME").unwrap()) != parent && !file.is_dir() {
         base_directory.push(parent_name);
     }
-    fs::create_dir_all(&base_directory)
-        .map_err(|err| ApplicationError::CouldNotCreateDirectories(base_directory.clone(), err))?;
     base_directory.push(
         file.file_name()
-            .ok_or(ApplicationError::FileNotFound(file.clone()))?,
+            .ok_or(ApplicationError::FileNotFound(file.to_path_buf()))?,
     );
     Ok(base_directory)
 }
 
+/// Asks a question on the terminal and returns the lowercased answer.
+fn prompt(question: &str) -> Result<String, ApplicationError> {
+    print!("{question}");
+    std::io::stdout()
+        .flush()
+        .map_err(ApplicationError::FailedReadingInput)?;
+    let mut answer = String::new();
+    std::io::stdin()
+        .read_line(&mut answer)
+        .map_err(ApplicationError::FailedReadingInput)?;
+    Ok(answer.trim().to_lowercase())
+}
+
+/// Boolean option within the `[options]` section of the configuration, unset meaning false.
+fn flag_option(config: &Ini, option: &str) -> Result<bool, ApplicationError> {
+    Ok(config
+        .getbool("options", option)
+        .map_err(|err| ApplicationError::InvalidOption(option.to_string(), err))?
+        .unwrap_or_default())
+}
+
+fn unsupported_action(action: &Action, primary_action: &PrimaryAction) -> ApplicationError {
+    let name = |value: Option<clap::builder::PossibleValue>| {
+        value.map_or_else(String::new, |value| value.get_name().to_string())
+    };
+    ApplicationError::UnsupportedAction(
+        name(action.to_possible_value()),
+        name(primary_action.to_possible_value()),
+    )
+}
+
+/// Parameters of a key generated by create-key or rotate-key.
+fn key_params(
+    algorithm: crypto::KeyAlgorithm,
+    user_id: &Option<String>,
+    expires_in: Option<u32>,
+) -> crypto::KeyParams {
+    crypto::KeyParams {
+        algorithm,
+        user_id: user_id
+            .clone()
+            .unwrap_or_else(|| match register::hostname() {
+                Some(hostname) => format!("dotfiles@{hostname}"),
+                None => "dotfiles".to_string(),
+            }),
+        expires_in,
+    }
+}
+
+/// Prints the parameters a key has been generated with.
+fn print_key_params(key: &crypto::Key, params: &crypto::KeyParams) {
+    println!("    fingerprint: {}", key.fingerprint());
+    println!("    algorithm:   {}", params.algorithm);
+    println!("    user id:     {}", params.user_id);
+    match key.expires_at() {
+        Some(expires_at) => println!("    expires:     {}", expires_at.format("%Y-%m-%d")),
+        None => println!("    expires:     never"),
+    }
+}
+
 fn key_or_cfg(key: &Option<PathBuf>, config: Ini) -> Result<PathBuf, ApplicationError> {
     match key {
         Some(key) => Ok(key.clone()),
         None => match config.get("options", "secret_key") {
             Some(config_key) => Ok(PathBuf::from(
                 shellexpand::full(&config_key)
-                    .map_err(|err| ApplicationError::ErrorExpandingVariable(err))?
+                    .map_err(ApplicationError::ErrorExpandingVariable)?
                     .deref(),
             )),
             None => Err(ApplicationError::SecretKeyRequired),
@@ -512,23 +793,20 @@ fn key_or_cfg(key: &Option<PathBuf>, config: Ini) -> Result<PathBuf, Application
     }
 }
 
-fn bkp_file(file: &PathBuf) -> Result<PathBuf, ApplicationError> {
-    let mut new_file = file.clone();
-    new_file.set_file_name(format!(
-        "{}.bkp-{}",
-        file.file_name().unwrap().to_str().unwrap(),
-        rand::thread_rng().next_u32()
-    ));
-    Ok(new_file)
-}
-
-fn expand_variables_in_path(file: &PathBuf) -> Result<PathBuf, ApplicationError> {
-    Ok(fs::canonicalize(
+fn expand_variables_in_path(file: &Path) -> Result<PathBuf, ApplicationError> {
+    let expanded = PathBuf::from(
         shellexpand::full(file.to_str().ok_or(ApplicationError::FileInputRequired)?)
-            .map_err(|err| ApplicationError::ErrorExpandingVariable(err))?
+            .map_err(ApplicationError::ErrorExpandingVariable)?
             .deref(),
-    )
-    .map_err(|_| ApplicationError::PathConversionError(file.clone()))?)
+    );
+    /* Symlinks are kept, a synced file is itself a symlink into the source control */
+    let absolute = match expanded.is_absolute() {
+        true => expanded,
+        false => std::env::current_dir()
+            .map_err(|_| ApplicationError::PathConversionError(file.to_path_buf()))?
+            .join(expanded),
+    };
+    Ok(register::normalize_path(&absolute))
 }
 
 #[cfg(test)]
@@ -536,8 +814,8 @@ mod tests {
     use super::*;
     use assert_fs::TempDir;
 
-    fn static_symlinc_dir<'a>(home: &'a str) -> PathBuf {
-        [home.into(), ".dotfiles", "symlinks"].iter().collect()
+    fn static_symlinc_dir(home: &str) -> PathBuf {
+        [home, ".dotfiles", "symlinks"].iter().collect()
     }
 
     #[test]
@@ -545,52 +823,114 @@ mod tests {
         let home = std::env::var("HOME").unwrap();
         let dotfile_path = dotfile_path(
             static_symlinc_dir(&home),
-            &[&home, ".dotfile"].iter().collect(),
+            &[&home, ".dotfile"].iter().collect::<PathBuf>(),
         )
         .unwrap();
         assert_eq!(
             dotfile_path,
-            [&home, ".dotfiles", "symlinks", ".dotfile"]
+            [&home, ".dotfiles", "symlinks", "home", ".dotfile"]
                 .iter()
                 .collect::<PathBuf>()
         );
     }
     #[test]
-    fn test_dotfile_folder() {
+    fn test_dotfile_nested_in_home() {
+        let home = std::env::var("HOME").unwrap();
+        let config = dotfile_path(
+            static_symlinc_dir(&home),
+            &[&home, ".config", "foo", "config"]
+                .iter()
+                .collect::<PathBuf>(),
+        )
+        .unwrap();
+        let local = dotfile_path(
+            static_symlinc_dir(&home),
+            &[&home, ".local", "foo", "config"]
+                .iter()
+                .collect::<PathBuf>(),
+        )
+        .unwrap();
+        assert_eq!(
+            config,
+            [
+                &home,
+                ".dotfiles",
+                "symlinks",
+                "home",
+                ".config",
+                "foo",
+                "config"
+            ]
+            .iter()
+            .collect::<PathBuf>()
+        );
+        assert_ne!(config, local);
+    }
+    #[test]
+    fn test_dotfile_outside_home() {
         let home = std::env::var("HOME").unwrap();
         let dotfile_path = dotfile_path(
             static_symlinc_dir(&home),
-            &[&home, ".config", "dotfolder"].iter().collect(),
+            &["/", "etc", "hosts"].iter().collect::<PathBuf>(),
         )
         .unwrap();
         assert_eq!(
             dotfile_path,
-            [&home, ".dotfiles", "symlinks", "dotfolder"]
+            [&home, ".dotfiles", "symlinks", "root", "etc", "hosts"]
                 .iter()
                 .collect::<PathBuf>()
         );
     }
     #[test]
-    fn test_dotfile_outside_home() {
+    fn test_dotfile_home_directory() {
         let home = std::env::var("HOME").unwrap();
-        let dotfile_path = dotfile_path(
+        assert!(dotfile_path(static_symlinc_dir(&home), Path::new(&home)).is_err());
+    }
+    #[test]
+    fn test_legacy_dotfile_in_home() {
+        let home = std::env::var("HOME").unwrap();
+        let dotfile_path = legacy_dotfile_path(
             static_symlinc_dir(&home),
-            &[&home, ".config", "dotfile"].iter().collect(),
+            &[&home, ".dotfile"].iter().collect::<PathBuf>(),
         )
         .unwrap();
         assert_eq!(
             dotfile_path,
-            [&home, ".dotfiles", "symlinks", ".config", "dotfile"]
+            [&home, ".dotfiles", "symlinks", ".dotfile"]
                 .iter()
                 .collect::<PathBuf>()
         );
     }
     #[test]
-    fn test_bkp_file() {
-        let file = ["file.txt"].iter().collect::<PathBuf>();
-        let bkp_file = bkp_file(&file).unwrap();
+    fn test_legacy_dotfile_folder() {
+        let home = std::env::var("HOME").unwrap();
+        let tmp_dir = TempDir::new().unwrap();
+        let dotfolder = tmp_dir.join("dotfolder");
+        fs::create_dir(&dotfolder).unwrap();
+        let dotfile_path = legacy_dotfile_path(static_symlinc_dir(&home), &dotfolder).unwrap();
+        assert_eq!(
+            dotfile_path,
+            [&home, ".dotfiles", "symlinks", "dotfolder"]
+                .iter()
+                .collect::<PathBuf>()
+        );
 
-        assert_ne!(file, bkp_file);
+        tmp_dir.close().unwrap();
+    }
+    #[test]
+    fn test_legacy_dotfile_outside_home() {
+        let home = std::env::var("HOME").unwrap();
+        let dotfile_path = legacy_dotfile_path(
+            static_symlinc_dir(&home),
+            &[&home, ".config", "dotfile"].iter().collect::<PathBuf>(),
+        )
+        .unwrap();
+        assert_eq!(
+            dotfile_path,
+            [&home, ".dotfiles", "symlinks", ".config", "dotfile"]
+                .iter()
+                .collect::<PathBuf>()
+        );
     }
     #[test]
     fn test_expand_tilde() {
@@ -607,6 +947,17 @@ mod tests {
         assert_eq!(expanded, PathBuf::from(home));
     }
     #[test]
+    fn test_expand_normalizes_without_resolving_symlinks() {
+        let tmp_dir = TempDir::new().unwrap();
+        let link = tmp_dir.join("link");
+        std::os::unix::fs::symlink(tmp_dir.join("target"), &link).unwrap();
+
+        let path = tmp_dir.join(".").join("other").join("..").join("link");
+        assert_eq!(expand_variables_in_path(&path).unwrap(), link);
+
+        tmp_dir.close().unwrap();
+    }
+    #[test]
     #[should_panic]
     fn test_no_key() {
         let config = Ini::new();
@@ -0,0 +1,1789 @@
+use std::{
+    collections::{BTreeSet, HashSet},
+    fmt, fs,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    backup::BackupSet,
+    cli::SyncDirection,
+    conflict::{self, ConflictStrategy, Resolution, Resolver},
+    create_missing_parents,
+    crypto::{self, Key},
+    dotignore::DotIgnore,
+    error::ApplicationError,
+    layout::{links_to, relative_target, resolves_to},
+    register::{Entry, LinkMode, Register},
+    state::{self, State},
+};
+
+/// A single filesystem operation performed by a sync.
+///
+/// A sync is first planned as a list of operations which is then either printed (dry-run) or
+/// executed, making the preview identical to what a real run does.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Operation {
+    /// Move a file from the filesystem into the source control
+    MoveToRepo { from: PathBuf, to: PathBuf },
+    /// Create a symlink at `link` pointing to `target` within the source control, either absolute
+    /// or relative to the directory containing the link
+    Symlink { target: PathBuf, link: PathBuf },
+    /// Move an existing file out of the way before it is replaced
+    Backup { from: PathBuf, to: PathBuf },
+    /// Encrypt a secret from the filesystem into the source control, leaving an existing encrypted
+    /// copy which already decrypts to the same content untouched when `compare` is set
+    Encrypt {
+        from: PathBuf,
+        to: PathBuf,
+        compare: bool,
+    },
+    /// Decrypt a secret from the source control onto the filesystem
+    Decrypt { from: PathBuf, to: PathBuf },
+    /// Apply the permissions of a register entry to a file
+    SetMode { path: PathBuf, mode: u32 },
+    /// Apply the owner of a register entry to a file, keeping the group when none is given
+    SetOwner {
+        path: PathBuf,
+        uid: u32,
+        gid: Option<u32>,
+    },
+    /// Create a real directory on the filesystem
+    CreateDir { path: PathBuf },
+    /// Remove a symlink at `link` pointing to `target`, as read from the link
+    Unlink { link: PathBuf, target: PathBuf },
+    /// Move a file from the source control back onto the filesystem
+    Restore { from: PathBuf, to: PathBuf },
+    /// Remove a file on the filesystem which is identical to `target` within the source control
+    Discard { path: PathBuf, target: PathBuf },
+    /// Copy the content of a file, in either direction
+    Copy { from: PathBuf, to: PathBuf },
+}
+
+impl Operation {
+    /// Performs the operation on the filesystem.
+    ///
+    /// The key is required for encryption and decryption.
+    pub(crate) fn execute(&self, key: Option<&Key>) -> Result<(), ApplicationError> {
+        match self {
+            Operation::MoveToRepo { from, to }
+            | Operation::Backup { from, to }
+            | Operation::Restore { from, to } => {
+                create_missing_parents(to)?;
+                fs::rename(from, to).map_err(|err| ApplicationError::FailedRenamingFile {
+                    err,
+                    from: from.clone(),
+                    to: to.clone(),
+                })
+            }
+            Operation::Symlink { target, link } => {
+                create_missing_parents(link)?;
+                std::os::unix::fs::symlink(target, link).map_err(|err| {
+                    ApplicationError::FailedRenamingFile {
+                        err,
+                        from: target.clone(),
+                        to: link.clone(),
+                    }
+                })
+            }
+            Operation::Encrypt { from, to, compare } => {
+                let key = key.ok_or(ApplicationError::SecretKeyRequired)?;
+                create_missing_parents(to)?;
+                match compare {
+                    true => crypto::encrypt_changed_file(from, to, key),
+                    false => crypto::encrypt_file(from, to, key),
+                }
+            }
+            Operation::Decrypt { from, to } => {
+                create_missing_parents(to)?;
+                crypto::decrypt_file(from, to, key.ok_or(ApplicationError::SecretKeyRequired)?)
+            }
+            Operation::SetMode { path, mode } => {
+                fs::set_permissions(path, fs::Permissions::from_mode(*mode))
+                    .map_err(|err| ApplicationError::FailedSettingPermissions(path.clone(), err))
+            }
+            Operation::SetOwner { path, uid, gid } => {
+                std::os::unix::fs::chown(path, Some(*uid), *gid)
+                    .map_err(|err| ApplicationError::FailedSettingOwner(path.clone(), err))
+            }
+            Operation::CreateDir { path } => fs::create_dir(path)
+                .map_err(|err| ApplicationError::CouldNotCreateDirectories(path.clone(), err)),
+            Operation::Copy { from, to } => {
+                create_missing_parents(to)?;
+                fs::copy(from, to)
+                    .map(|_| ())
+                    .map_err(|err| ApplicationError::FailedRenamingFile {
+                        err,
+                        from: from.clone(),
+                        to: to.clone(),
+                    })
+            }
+            Operation::Discard { path, .. } => fs::remove_file(path)
+                .map_err(|err| ApplicationError::FailedRemovingFile(path.clone(), err)),
+            Operation::Unlink { link, target } => {
+                if !fs::read_link(link).is_ok_and(|link_target| &link_target == target) {
+                    return Err(ApplicationError::UntrackedSymlinkedFile(link.clone()));
+                }
+                fs::remove_file(link)
+                    .map_err(|err| ApplicationError::FailedRemovingFile(link.clone(), err))
+            }
+        }
+    }
+
+    /// Reverts the operation if it has taken effect on the filesystem.
+    ///
+    /// The filesystem is inspected before anything is reverted, making it safe to undo an
+    /// operation which was interrupted or never performed.
+    pub(crate) fn undo(&self) -> Result<(), ApplicationError> {
+        match self {
+            Operation::MoveToRepo { from, to }
+            | Operation::Backup { from, to }
+            | Operation::Restore { from, to } => {
+                if fs::symlink_metadata(to).is_ok() && fs::symlink_metadata(from).is_err() {
+                    fs::rename(to, from).map_err(|err| ApplicationError::FailedRenamingFile {
+                        err,
+                        from: to.clone(),
+                        to: from.clone(),
+                    })?;
+                }
+            }
+            Operation::Symlink { target, link } => {
+                if fs::read_link(link).is_ok_and(|link_target| &link_target == target) {
+                    fs::remove_file(link)
+                        .map_err(|err| ApplicationError::FailedRemovingFile(link.clone(), err))?;
+                }
+            }
+            /* The encrypted copy only lives within the source control which keeps its history */
+            Operation::Encrypt { .. } => {}
+            Operation::Decrypt { to, .. } => {
+                /* Any existing file is backed up before decryption, meaning a file at the
+                 * destination was written by the decryption */
+                if fs::symlink_metadata(to).is_ok() {
+                    fs::remove_file(to)
+                        .map_err(|err| ApplicationError::FailedRemovingFile(to.clone(), err))?;
+                }
+            }
+            /* Files created by the sync are removed by undoing earlier operations */
+            Operation::SetMode { .. } | Operation::SetOwner { .. } => {}
+            Operation::CreateDir { path } => {
+                /* Everything created within the directory has been undone already */
+                if fs::symlink_metadata(path).is_ok_and(|metadata| metadata.is_dir()) {
+                    fs::remove_dir(path)
+                        .map_err(|err| ApplicationError::FailedRemovingFile(path.clone(), err))?;
+                }
+            }
+            /* Anything at the destination has been backed up before copying */
+            Operation::Copy { to, .. } => {
+                if fs::symlink_metadata(to).is_ok() {
+                    fs::remove_file(to)
+                        .map_err(|err| ApplicationError::FailedRemovingFile(to.clone(), err))?;
+                }
+            }
+            /* The content is still present within the source control */
+            Operation::Discard { path, target } => {
+                if fs::symlink_metadata(path).is_err() {
+                    fs::copy(target, path).map_err(|err| ApplicationError::FailedRenamingFile {
+                        err,
+                        from: target.clone(),
+                        to: path.clone(),
+                    })?;
+                }
+            }
+            Operation::Unlink { link, target } => {
+                if fs::symlink_metadata(link).is_err() {
+                    std::os::unix::fs::symlink(target, link).map_err(|err| {
+                        ApplicationError::FailedRenamingFile {
+                            err,
+                            from: target.clone(),
+                            to: link.clone(),
+                        }
+                    })?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn requires_key(&self) -> bool {
+        matches!(self, Operation::Encrypt { .. } | Operation::Decrypt { .. })
+    }
+
+    /// Whether the key has to be unlocked, which encryption alone does not need.
+    pub(crate) fn requires_passphrase(&self) -> bool {
+        matches!(
+            self,
+            Operation::Decrypt { .. } | Operation::Encrypt { compare: true, .. }
+        )
+    }
+
+    /// Serializes the operation into a single tab separated journal line.
+    pub(crate) fn to_journal_line(&self) -> Result<String, ApplicationError> {
+        let path_str = |path: &Path| {
+            path.to_str()
+                .map(str::to_string)
+                .ok_or(ApplicationError::PathConversionError(path.to_path_buf()))
+        };
+        let (action, first, second) = match self {
+            Operation::MoveToRepo { from, to } => ("move", path_str(from)?, path_str(to)?),
+            Operation::Symlink { target, link } => ("symlink", path_str(link)?, path_str(target)?),
+            Operation::Backup { from, to } => ("backup", path_str(from)?, path_str(to)?),
+            Operation::Encrypt {
+                from,
+                to,
+                compare: false,
+            } => ("encrypt", path_str(from)?, path_str(to)?),
+            Operation::Encrypt {
+                from,
+                to,
+                compare: true,
+            } => ("encrypt-changed", path_str(from)?, path_str(to)?),
+            Operation::Decrypt { from, to } => ("decrypt", path_str(from)?, path_str(to)?),
+            Operation::SetMode { path, mode } => ("chmod", path_str(path)?, format!("{mode:o}")),
+            Operation::SetOwner { path, uid, gid } => {
+                ("chown", path_str(path)?, format_owner(*uid, *gid))
+            }
+            Operation::CreateDir { path } => ("mkdir", path_str(path)?, String::new()),
+            Operation::Unlink { link, target } => ("unlink", path_str(link)?, path_str(target)?),
+            Operation::Restore { from, to } => ("restore", path_str(from)?, path_str(to)?),
+            Operation::Discard { path, target } => ("discard", path_str(path)?, path_str(target)?),
+            Operation::Copy { from, to } => ("copy", path_str(from)?, path_str(to)?),
+        };
+        Ok(format!("{action}\t{first}\t{second}"))
+    }
+
+    /// Inverse of [`Operation::to_journal_line`].
+    pub(crate) fn from_journal_line(line: &str) -> Option<Self> {
+        let mut fields = line.split('\t');
+        let (action, first, second) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(action), Some(first), Some(second)) if fields.next().is_none() => {
+                (action, PathBuf::from(first), second)
+            }
+            _ => return None,
+        };
+        Some(match action {
+            "move" => Operation::MoveToRepo {
+                from: first,
+                to: PathBuf::from(second),
+            },
+            "symlink" => Operation::Symlink {
+                target: PathBuf::from(second),
+                link: first,
+            },
+            "backup" => Operation::Backup {
+                from: first,
+                to: PathBuf::from(second),
+            },
+            "encrypt" | "encrypt-changed" => Operation::Encrypt {
+                from: first,
+                to: PathBuf::from(second),
+                compare: action == "encrypt-changed",
+            },
+            "decrypt" => Operation::Decrypt {
+                from: first,
+                to: PathBuf::from(second),
+            },
+            "chmod" => Operation::SetMode {
+                path: first,
+                mode: u32::from_str_radix(second, 8).ok()?,
+            },
+            "chown" => {
+                let (uid, gid) = match second.split_once(':') {
+                    Some((uid, gid)) => (uid, Some(gid.parse().ok()?)),
+                    None => (second, None),
+                };
+                Operation::SetOwner {
+                    path: first,
+                    uid: uid.parse().ok()?,
+                    gid,
+                }
+            }
+            "mkdir" => Operation::CreateDir { path: first },
+            "unlink" => Operation::Unlink {
+                link: first,
+                target: PathBuf::from(second),
+            },
+            "restore" => Operation::Restore {
+                from: first,
+                to: PathBuf::from(second),
+            },
+            "discard" => Operation::Discard {
+                path: first,
+                target: PathBuf::from(second),
+            },
+            "copy" => Operation::Copy {
+                from: first,
+                to: PathBuf::from(second),
+            },
+            _ => return None,
+        })
+    }
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (action, from, to) = match self {
+            Operation::MoveToRepo { from, to } => ("move", from, to),
+            Operation::Symlink { target, link } => ("symlink", link, target),
+            Operation::Backup { from, to } => ("backup", from, to),
+            Operation::Encrypt { from, to, .. } => ("encrypt", from, to),
+            Operation::Decrypt { from, to } => ("decrypt", from, to),
+            Operation::Unlink { link, target } => ("unlink", link, target),
+            Operation::Restore { from, to } => ("restore", from, to),
+            Operation::Discard { path, target } => ("discard", path, target),
+            Operation::Copy { from, to } => ("copy", from, to),
+            Operation::SetMode { path, mode } => {
+                return write!(f, "{:8} {} -> {:o}", "chmod", path.display(), mode)
+            }
+            Operation::SetOwner { path, uid, gid } => {
+                return write!(
+                    f,
+                    "{:8} {} -> {}",
+                    "chown",
+                    path.display(),
+                    format_owner(*uid, *gid)
+                )
+            }
+            Operation::CreateDir { path } => return write!(f, "{:8} {}", "mkdir", path.display()),
+        };
+        write!(f, "{:8} {} -> {}", action, from.display(), to.display())
+    }
+}
+
+/// Owner as written by `chown`, e.g. `1000` or `1000:1000`.
+fn format_owner(uid: u32, gid: Option<u32>) -> String {
+    match gid {
+        Some(gid) => format!("{uid}:{gid}"),
+        None => uid.to_string(),
+    }
+}
+
+/// Stores consulted while planning a sync.
+#[derive(Clone, Copy)]
+pub(crate) struct Context<'a> {
+    /// Backup set receiving every file replaced by the sync
+    pub(crate) backups: &'a BackupSet,
+    pub(crate) resolver: &'a Resolver,
+    pub(crate) state: &'a State,
+    /// Create symlinks relative to their location, unless overridden by the entry
+    pub(crate) relative_symlinks: bool,
+}
+
+impl Context<'_> {
+    /// Symlink at `link` to `dotfile`, relative when configured.
+    fn symlink(&self, dotfile: &Path, link: &Path) -> Operation {
+        Operation::Symlink {
+            target: match self.relative_symlinks {
+                true => relative_target(link, dotfile),
+                false => dotfile.to_path_buf(),
+            },
+            link: link.to_path_buf(),
+        }
+    }
+}
+
+/// Operations planned for a single register entry, or the reason the entry cannot be synced.
+///
+/// Every entry is executed on its own, so a failing entry does not hold back the others.
+#[derive(Debug)]
+pub(crate) struct EntryPlan {
+    pub(crate) path: PathBuf,
+    pub(crate) operations: Result<Vec<Operation>, ApplicationError>,
+}
+
+/// Number of entries per outcome of a sync.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct Summary {
+    pub(crate) synced: usize,
+    /// Entries which were already in sync or were left alone
+    pub(crate) skipped: usize,
+    pub(crate) failed: usize,
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} synced, {} skipped, {} failed",
+            self.synced, self.skipped, self.failed
+        )
+    }
+}
+
+/// Plans the operations needed to sync every active entry within the symlinks register.
+pub(crate) fn plan_symlinks(
+    direction: &SyncDirection,
+    dotfiles_dir: &Path,
+    register: &Register,
+    tags: &[String],
+    context: &Context,
+) -> Result<Vec<EntryPlan>, ApplicationError> {
+    let dotignore = DotIgnore::load(dotfiles_dir)?;
+    Ok(register
+        .active_entries(tags)
+        .map(|entry| EntryPlan {
+            path: entry.path.clone(),
+            operations: plan_symlink_entry(direction, dotfiles_dir, entry, &dotignore, context),
+        })
+        .collect())
+}
+
+fn plan_symlink_entry(
+    direction: &SyncDirection,
+    dotfiles_dir: &Path,
+    entry: &Entry,
+    dotignore: &DotIgnore,
+    context: &Context,
+) -> Result<Vec<Operation>, ApplicationError> {
+    /* A tracked file contain two locations, one for the symlink and one for the real file */
+    let dotfile_path = entry.dotfile_path(dotfiles_dir.join("symlinks"))?;
+    let context = &Context {
+        relative_symlinks: entry.relative.unwrap_or(context.relative_symlinks),
+        ..*context
+    };
+    let mut operations = match entry.link_mode() {
+        LinkMode::Symlink if contains_ignored(dotignore, &entry.path)? => {
+            println!(
+                "'{}' contains ignored files, linking the files within individually",
+                entry.path.display()
+            );
+            plan_stow(direction, &entry.path, &dotfile_path, dotignore, context)?
+        }
+        LinkMode::Symlink => plan_symlink(direction, &entry.path, dotfile_path, context)?,
+        LinkMode::Stow => plan_stow(direction, &entry.path, &dotfile_path, dotignore, context)?,
+        LinkMode::Copy => plan_copy(direction, &entry.path, &dotfile_path, context)?,
+    };
+    if operations.is_empty() {
+        return Ok(operations);
+    }
+    if let Some(mode) = entry.mode()? {
+        operations.push(Operation::SetMode {
+            path: entry.path.clone(),
+            mode,
+        });
+    }
+    Ok(operations)
+}
+
+/// Whether a real directory on the filesystem contains files excluded by a `.dotignore`, which
+/// would end up within the source control if the whole directory was moved.
+pub(crate) fn contains_ignored(
+    dotignore: &DotIgnore,
+    path: &Path,
+) -> Result<bool, ApplicationError> {
+    if !fs::symlink_metadata(path).is_ok_and(|metadata| metadata.is_dir()) {
+        return Ok(false);
+    }
+    Ok(!dotignore.walk(path)?.ignored.is_empty())
+}
+
+/// Plans replacing a tracked file or directory with a single symlink into the source control.
+fn plan_symlink(
+    direction: &SyncDirection,
+    file: &Path,
+    dotfile_path: PathBuf,
+    context: &Context,
+) -> Result<Vec<Operation>, ApplicationError> {
+    if file.is_symlink() {
+        if !resolves_to(file, &dotfile_path) {
+            return Err(ApplicationError::UntrackedSymlinkedFile(file.to_path_buf()));
+        }
+        if !dotfile_path
+            .try_exists()
+            .map_err(ApplicationError::FailedCheckingExistanceOfFile)?
+        {
+            return Err(ApplicationError::FileNotFound(dotfile_path));
+        }
+        return Ok(Vec::new());
+    }
+
+    let mut operations = Vec::new();
+    match direction {
+        SyncDirection::Dotfiles => {
+            if !file
+                .try_exists()
+                .map_err(ApplicationError::FailedCheckingExistanceOfFile)?
+            {
+                eprintln!("{}", ApplicationError::FileNotFound(file.to_path_buf()));
+                return Ok(Vec::new());
+            }
+            operations.push(Operation::MoveToRepo {
+                from: file.to_path_buf(),
+                to: dotfile_path.clone(),
+            });
+        }
+        SyncDirection::Filesystem => {
+            if !file
+                .try_exists()
+                .map_err(ApplicationError::FailedCheckingExistanceOfFile)?
+            {
+                return Ok(Vec::new());
+            }
+            return plan_replace(file, &dotfile_path, context);
+        }
+        SyncDirection::Auto => {
+            if fs::symlink_metadata(&dotfile_path).is_err() {
+                return plan_symlink(&SyncDirection::Dotfiles, file, dotfile_path, context);
+            }
+            if fs::symlink_metadata(file).is_ok() {
+                return plan_auto_replace(file, &dotfile_path, context);
+            }
+        }
+    }
+
+    operations.push(context.symlink(&dotfile_path, file));
+    Ok(operations)
+}
+
+/// Plans replacing a file present on both sides for `--sync-direction auto`, keeping the side
+/// which changed since the content recorded when it was last synced.
+fn plan_auto_replace(
+    file: &Path,
+    dotfile: &Path,
+    context: &Context,
+) -> Result<Vec<Operation>, ApplicationError> {
+    let (local, repo) = (state::hash_tree(file)?, state::hash_tree(dotfile)?);
+    let recorded = context.state.synced.get(file);
+    let strategy = if local == repo || recorded == Some(&local) {
+        ConflictStrategy::TakeRepo
+    } else if recorded == Some(&repo) {
+        ConflictStrategy::KeepLocal
+    } else {
+        return Err(ApplicationError::BothSidesChanged(file.to_path_buf()));
+    };
+    let resolver = Resolver {
+        strategy,
+        dry_run: context.resolver.dry_run,
+    };
+    plan_replace(
+        file,
+        dotfile,
+        &Context {
+            resolver: &resolver,
+            ..*context
+        },
+    )
+}
+
+/// Plans replacing an existing file on the filesystem with a symlink to its copy within the
+/// source control, resolving differing content first.
+///
+/// Identical files are removed without a backup, since their content stays within the source
+/// control.
+fn plan_replace(
+    file: &Path,
+    dotfile: &Path,
+    context: &Context,
+) -> Result<Vec<Operation>, ApplicationError> {
+    let symlink = context.symlink(dotfile, file);
+    if !file.is_symlink() && fs::symlink_metadata(dotfile).is_ok() {
+        if conflict::identical(file, dotfile)? {
+            if file.is_file() {
+                return Ok(vec![
+                    Operation::Discard {
+                        path: file.to_path_buf(),
+                        target: dotfile.to_path_buf(),
+                    },
+                    symlink,
+                ]);
+            }
+        } else {
+            match context.resolver.resolve(file, dotfile)? {
+                Resolution::Skip => return Ok(Vec::new()),
+                Resolution::KeepLocal => {
+                    return Ok(vec![
+                        Operation::Backup {
+                            from: dotfile.to_path_buf(),
+                            to: context.backups.path_for(dotfile),
+                        },
+                        Operation::MoveToRepo {
+                            from: file.to_path_buf(),
+                            to: dotfile.to_path_buf(),
+                        },
+                        symlink,
+                    ])
+                }
+                Resolution::TakeRepo => {}
+            }
+        }
+    }
+    Ok(vec![
+        Operation::Backup {
+            from: file.to_path_buf(),
+            to: context.backups.path_for(file),
+        },
+        symlink,
+    ])
+}
+
+/// Plans linking every file within a tracked directory on its own, keeping the directories on
+/// the filesystem real so applications can store other files next to the tracked ones.
+///
+/// Files only present within the source control are linked in both directions, files only present
+/// on the filesystem are moved into the source control when syncing towards the dotfiles. Files
+/// excluded by a `.dotignore` are left in place.
+fn plan_stow(
+    direction: &SyncDirection,
+    directory: &Path,
+    dotfile_directory: &Path,
+    dotignore: &DotIgnore,
+    context: &Context,
+) -> Result<Vec<Operation>, ApplicationError> {
+    let mut operations = Vec::new();
+    let mut local_is_dir = false;
+    match fs::symlink_metadata(directory) {
+        Ok(metadata) if metadata.is_symlink() && !resolves_to(directory, dotfile_directory) => {
+            return Err(ApplicationError::UntrackedSymlinkedFile(
+                directory.to_path_buf(),
+            ))
+        }
+        Ok(metadata) if metadata.is_symlink() => operations.push(Operation::Backup {
+            to: context.backups.path_for(directory),
+            from: directory.to_path_buf(),
+        }),
+        Ok(metadata) if metadata.is_dir() => local_is_dir = true,
+        Ok(_) => return Err(ApplicationError::NotADirectory(directory.to_path_buf())),
+        Err(_) if matches!(direction, SyncDirection::Dotfiles) => {
+            eprintln!(
+                "{}",
+                ApplicationError::FileNotFound(directory.to_path_buf())
+            );
+            return Ok(Vec::new());
+        }
+        Err(_) => {}
+    }
+
+    let mut files = BTreeSet::new();
+    if local_is_dir {
+        files.extend(dotignore.walk(directory)?.files);
+    }
+    if dotfile_directory.is_dir() {
+        files.extend(dotignore.walk(dotfile_directory)?.files);
+    }
+
+    let mut created_dirs = HashSet::new();
+    for relative in files {
+        let file = directory.join(&relative);
+        let dotfile = dotfile_directory.join(&relative);
+        let local = match local_is_dir {
+            true => fs::symlink_metadata(&file).ok(),
+            false => None,
+        };
+        if local.as_ref().is_some_and(|metadata| metadata.is_symlink()) {
+            if resolves_to(&file, &dotfile) {
+                continue;
+            }
+            return Err(ApplicationError::UntrackedSymlinkedFile(file));
+        }
+
+        match direction {
+            SyncDirection::Auto if local.is_some() && fs::symlink_metadata(&dotfile).is_ok() => {
+                operations.extend(plan_auto_replace(&file, &dotfile, context)?);
+                continue;
+            }
+            SyncDirection::Dotfiles | SyncDirection::Auto => {
+                if local.is_some() {
+                    operations.push(Operation::MoveToRepo {
+                        from: file.clone(),
+                        to: dotfile.clone(),
+                    });
+                }
+            }
+            SyncDirection::Filesystem => {
+                if fs::symlink_metadata(&dotfile).is_err() {
+                    continue;
+                }
+                if local.is_some() {
+                    operations.extend(plan_replace(&file, &dotfile, context)?);
+                    continue;
+                }
+            }
+        }
+
+        /* Directories leading to the file are recreated as real directories, when they are
+         * missing on the filesystem */
+        let mut missing_dirs = file
+            .ancestors()
+            .skip(1)
+            .take_while(|ancestor| ancestor.starts_with(directory))
+            .filter(|ancestor| {
+                !local_is_dir || !fs::symlink_metadata(ancestor).is_ok_and(|m| m.is_dir())
+            })
+            .map(Path::to_path_buf)
+            .collect::<Vec<_>>();
+        missing_dirs.reverse();
+        for missing_dir in missing_dirs {
+            if created_dirs.insert(missing_dir.clone()) {
+                operations.push(Operation::CreateDir { path: missing_dir });
+            }
+        }
+        operations.push(context.symlink(&dotfile, &file));
+    }
+    Ok(operations)
+}
+
+/// Plans deploying a tracked file as a copy of the file within the source control.
+///
+/// The hash recorded when the file was last synced tells local edits apart from changes within
+/// the source control. Local edits are pulled into the source control when syncing towards the
+/// dotfiles, and are resolved as a conflict when syncing towards the filesystem.
+fn plan_copy(
+    direction: &SyncDirection,
+    file: &Path,
+    dotfile: &Path,
+    context: &Context,
+) -> Result<Vec<Operation>, ApplicationError> {
+    let local = fs::symlink_metadata(file).ok();
+    if local.as_ref().is_some_and(|metadata| metadata.is_dir()) || dotfile.is_dir() {
+        return Err(ApplicationError::CopyModeDirectory(file.to_path_buf()));
+    }
+    let deploy = Operation::Copy {
+        from: dotfile.to_path_buf(),
+        to: file.to_path_buf(),
+    };
+    let pull = vec![
+        Operation::Backup {
+            from: dotfile.to_path_buf(),
+            to: context.backups.path_for(dotfile),
+        },
+        Operation::Copy {
+            from: file.to_path_buf(),
+            to: dotfile.to_path_buf(),
+        },
+    ];
+    let replace = vec![
+        Operation::Backup {
+            from: file.to_path_buf(),
+            to: context.backups.path_for(file),
+        },
+        deploy.clone(),
+    ];
+
+    /* A symlink left by an earlier link mode is replaced by the copy */
+    if local.as_ref().is_some_and(|metadata| metadata.is_symlink()) {
+        if let Some(target) = fs::read_link(file).ok().filter(|_| links_to(file, dotfile)) {
+            return Ok(vec![
+                Operation::Unlink {
+                    link: file.to_path_buf(),
+                    target,
+                },
+                deploy,
+            ]);
+        }
+        return Err(ApplicationError::UntrackedSymlinkedFile(file.to_path_buf()));
+    }
+
+    match (local.is_some(), dotfile.is_file(), direction) {
+        (false, _, SyncDirection::Dotfiles) | (false, false, SyncDirection::Auto) => {
+            eprintln!("{}", ApplicationError::FileNotFound(file.to_path_buf()));
+            return Ok(Vec::new());
+        }
+        (true, false, SyncDirection::Dotfiles | SyncDirection::Auto) => {
+            return Ok(vec![Operation::Copy {
+                from: file.to_path_buf(),
+                to: dotfile.to_path_buf(),
+            }])
+        }
+        (false, true, SyncDirection::Filesystem | SyncDirection::Auto) => return Ok(vec![deploy]),
+        (_, false, SyncDirection::Filesystem) => return Ok(Vec::new()),
+        (true, true, _) => {}
+    }
+
+    let (hash, dotfile_hash) = (state::hash_file(file)?, state::hash_file(dotfile)?);
+    if hash == dotfile_hash {
+        return Ok(Vec::new());
+    }
+    let modified_locally = context.state.copies.get(file) != Some(&hash);
+    match (direction, modified_locally) {
+        (SyncDirection::Auto, false) => Ok(replace),
+        (SyncDirection::Auto, true) if context.state.copies.get(file) == Some(&dotfile_hash) => {
+            Ok(pull)
+        }
+        (SyncDirection::Auto, true) => Err(ApplicationError::BothSidesChanged(file.to_path_buf())),
+        (SyncDirection::Dotfiles, true) => Ok(pull),
+        (SyncDirection::Dotfiles, false) => {
+            println!(
+                "'{}' has changed within the source control, sync towards the filesystem to deploy it",
+                file.display()
+            );
+            Ok(Vec::new())
+        }
+        (SyncDirection::Filesystem, false) => Ok(replace),
+        (SyncDirection::Filesystem, true) => match context.resolver.resolve(file, dotfile)? {
+            Resolution::Skip => Ok(Vec::new()),
+            Resolution::KeepLocal => Ok(pull),
+            Resolution::TakeRepo => Ok(replace),
+        },
+    }
+}
+
+/// Plans the operations needed to sync every active entry within the secrets register.
+pub(crate) fn plan_secrets(
+    direction: &SyncDirection,
+    dotfiles_dir: &Path,
+    register: &Register,
+    tags: &[String],
+    context: &Context,
+) -> Vec<EntryPlan> {
+    register
+        .active_entries(tags)
+        .map(|entry| EntryPlan {
+            path: entry.path.clone(),
+            operations: plan_secret(direction, dotfiles_dir, entry, context),
+        })
+        .collect()
+}
+
+fn plan_secret(
+    direction: &SyncDirection,
+    dotfiles_dir: &Path,
+    entry: &Entry,
+    context: &Context,
+) -> Result<Vec<Operation>, ApplicationError> {
+    let file = entry.path.clone();
+    let dotfile_path = entry.dotfile_path(dotfiles_dir.join("secrets"))?;
+    let mut operations = Vec::new();
+    match direction {
+        SyncDirection::Dotfiles => {
+            if !file
+                .try_exists()
+                .map_err(ApplicationError::FailedCheckingExistanceOfFile)?
+            {
+                return Err(ApplicationError::FileNotFound(file));
+            }
+            warn_if_readable(&file)?;
+            /* Encryption is randomized, so encrypting an unchanged secret again would only add
+             * noise to the history of the source control. Without recorded hashes, e.g. on a fresh
+             * clone, the existing encrypted copy is decrypted and compared instead */
+            let exists = fs::symlink_metadata(&dotfile_path).is_ok();
+            let recorded = context.state.secrets.get(&file);
+            if let Some(recorded) = recorded.filter(|_| exists) {
+                match recorded.changes(&file, &dotfile_path)? {
+                    (false, false) => return Ok(operations),
+                    (false, true) => return Err(ApplicationError::EncryptedCopyChanged(file)),
+                    (true, true) => return Err(ApplicationError::BothSidesChanged(file)),
+                    (true, false) => {}
+                }
+            }
+            operations.push(Operation::Encrypt {
+                from: file,
+                to: dotfile_path,
+                compare: exists && recorded.is_none(),
+            })
+        }
+        SyncDirection::Filesystem => {
+            if !dotfile_path
+                .try_exists()
+                .map_err(ApplicationError::FailedCheckingExistanceOfFile)?
+            {
+                return Err(ApplicationError::FileNotFound(dotfile_path));
+            }
+            if file
+                .try_exists()
+                .map_err(ApplicationError::FailedCheckingExistanceOfFile)?
+            {
+                operations.push(Operation::Backup {
+                    to: context.backups.path_for(&file),
+                    from: file.clone(),
+                });
+            }
+            operations.push(Operation::Decrypt {
+                from: dotfile_path,
+                to: file.clone(),
+            });
+            operations.extend(plan_secret_metadata(entry)?);
+        }
+        SyncDirection::Auto => {
+            if let Some(direction) = secret_direction(&file, &dotfile_path, context)? {
+                return plan_secret(&direction, dotfiles_dir, entry, context);
+            }
+        }
+    }
+    Ok(operations)
+}
+
+/// Plans applying the owner and permissions recorded for a secret to its decrypted plaintext,
+/// which is created readable by its owner only otherwise.
+fn plan_secret_metadata(entry: &Entry) -> Result<Vec<Operation>, ApplicationError> {
+    let mut operations = Vec::new();
+    /* Changing the owner may clear the setuid and setgid bits, so it goes first */
+    if let Some((uid, gid)) = entry.owner()? {
+        operations.push(Operation::SetOwner {
+            path: entry.path.clone(),
+            uid,
+            gid,
+        });
+    }
+    if let Some(mode) = entry.mode()? {
+        if mode & 0o044 != 0 {
+            eprintln!(
+                "Warning: the permissions {:o} recorded for '{}' make it readable by other users",
+                mode,
+                entry.path.display()
+            );
+        }
+        operations.push(Operation::SetMode {
+            path: entry.path.clone(),
+            mode,
+        });
+    }
+    Ok(operations)
+}
+
+/// Warns when the plaintext of a secret is readable by its group or by every user.
+pub(crate) fn warn_if_readable(file: &Path) -> Result<(), ApplicationError> {
+    let mode = fs::metadata(file)
+        .map_err(|err| ApplicationError::CouldNotOpenFile(file.to_path_buf(), err))?
+        .permissions()
+        .mode();
+    if mode & 0o044 != 0 {
+        eprintln!(
+            "Warning: '{}' is readable by other users (permissions {:o}), consider `chmod 600`",
+            file.display(),
+            mode & 0o7777
+        );
+    }
+    Ok(())
+}
+
+/// Direction a secret is synced in by `--sync-direction auto`, `None` when neither side has
+/// changed since it was last synced.
+fn secret_direction(
+    file: &Path,
+    dotfile: &Path,
+    context: &Context,
+) -> Result<Option<SyncDirection>, ApplicationError> {
+    match (
+        fs::symlink_metadata(file).is_ok(),
+        fs::symlink_metadata(dotfile).is_ok(),
+    ) {
+        (false, _) => return Ok(Some(SyncDirection::Filesystem)),
+        (true, false) => return Ok(Some(SyncDirection::Dotfiles)),
+        (true, true) => {}
+    }
+    match secret_changes(file, dotfile, context)? {
+        (false, false) => Ok(None),
+        (true, false) => Ok(Some(SyncDirection::Dotfiles)),
+        (false, true) => Ok(Some(SyncDirection::Filesystem)),
+        (true, true) => Err(ApplicationError::BothSidesChanged(file.to_path_buf())),
+    }
+}
+
+/// Whether the plaintext and the encrypted copy of a secret, both existing, have changed since it
+/// was last synced.
+fn secret_changes(
+    file: &Path,
+    dotfile: &Path,
+    context: &Context,
+) -> Result<(bool, bool), ApplicationError> {
+    match context.state.secrets.get(file) {
+        Some(recorded) => recorded.changes(file, dotfile),
+        None => Ok((true, true)),
+    }
+}
+
+/// Plans moving a tracked config file out of the source control, replacing the symlink on the
+/// filesystem with the file itself.
+///
+/// Directories which are real on the filesystem are restored file by file. A file within the
+/// source control is left in place when a real file already exists at its location.
+pub(crate) fn plan_untrack(
+    file: &Path,
+    dotfile: &Path,
+) -> Result<Vec<Operation>, ApplicationError> {
+    let Ok(dotfile_metadata) = fs::symlink_metadata(dotfile) else {
+        return Ok(Vec::new());
+    };
+    let metadata = match fs::symlink_metadata(file) {
+        Ok(metadata) => metadata,
+        Err(_) => {
+            return Ok(vec![Operation::Restore {
+                from: dotfile.to_path_buf(),
+                to: file.to_path_buf(),
+            }])
+        }
+    };
+    if let Some(target) = fs::read_link(file).ok().filter(|_| links_to(file, dotfile)) {
+        return Ok(vec![
+            Operation::Unlink {
+                link: file.to_path_buf(),
+                target,
+            },
+            Operation::Restore {
+                from: dotfile.to_path_buf(),
+                to: file.to_path_buf(),
+            },
+        ]);
+    }
+    if metadata.is_dir() && dotfile_metadata.is_dir() {
+        let mut operations = Vec::new();
+        let mut entries = fs::read_dir(dotfile)
+            .map_err(|err| ApplicationError::CouldNotOpenFile(dotfile.to_path_buf(), err))?
+            .map(|entry| entry.map(|entry| entry.file_name()))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| ApplicationError::CouldNotOpenFile(dotfile.to_path_buf(), err))?;
+        entries.sort();
+        for name in entries {
+            operations.extend(plan_untrack(&file.join(&name), &dotfile.join(&name))?);
+        }
+        return Ok(operations);
+    }
+
+    eprintln!(
+        "'{}' exists on the filesystem, '{}' is left in the source control",
+        file.display(),
+        dotfile.display()
+    );
+    Ok(Vec::new())
+}
+
+/// Plans moving the copy within the source control onto the filesystem when the deployed copy
+/// is missing.
+pub(crate) fn plan_untrack_copy(file: &Path, dotfile: &Path) -> Vec<Operation> {
+    match fs::symlink_metadata(file).is_err() && dotfile.is_file() {
+        true => vec![Operation::Restore {
+            from: dotfile.to_path_buf(),
+            to: file.to_path_buf(),
+        }],
+        false => Vec::new(),
+    }
+}
+
+/// Plans decrypting a tracked secret onto the filesystem before its encrypted copy is removed
+/// from the source control, when the plaintext is missing.
+pub(crate) fn plan_untrack_secret(
+    entry: &Entry,
+    dotfile: &Path,
+) -> Result<Vec<Operation>, ApplicationError> {
+    if fs::symlink_metadata(&entry.path).is_ok() || fs::symlink_metadata(dotfile).is_err() {
+        return Ok(Vec::new());
+    }
+    let mut operations = vec![Operation::Decrypt {
+        from: dotfile.to_path_buf(),
+        to: entry.path.clone(),
+    }];
+    operations.extend(plan_secret_metadata(entry)?);
+    Ok(operations)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conflict::ConflictStrategy;
+    use crate::register::RegisterFormat;
+    use crate::state::SecretHashes;
+    use assert_fs::TempDir;
+
+    fn flatten(plans: Vec<EntryPlan>) -> Vec<Operation> {
+        plans
+            .into_iter()
+            .flat_map(|plan| plan.operations.unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_plan_dotfiles_moves_and_links() {
+        let tmp_dir = TempDir::new().unwrap();
+        let dotfiles_dir = tmp_dir.join("dotfiles");
+        let file = tmp_dir.join("config").join("file");
+        create_missing_parents(&file).unwrap();
+        fs::write(&file, "content").unwrap();
+        let register = Register {
+            entries: vec![Entry::new(file.clone())],
+            format: RegisterFormat::Structured,
+        };
+
+        let operations = flatten(
+            plan_symlinks(
+                &SyncDirection::Dotfiles,
+                &dotfiles_dir,
+                &register,
+                &[],
+                &Context {
+                    backups: &BackupSet::new(&tmp_dir.join("backups")),
+                    resolver: &Resolver::default(),
+                    state: &State::default(),
+                    relative_symlinks: false,
+                },
+            )
+            .unwrap(),
+        );
+        let dotfile = crate::dotfile_path(dotfiles_dir.join("symlinks"), &file).unwrap();
+        assert_eq!(
+            operations,
+            vec![
+                Operation::MoveToRepo {
+                    from: file.clone(),
+                    to: dotfile.clone()
+                },
+                Operation::Symlink {
+                    target: dotfile.clone(),
+                    link: file.clone()
+                },
+            ]
+        );
+        assert!(!dotfile.exists());
+
+        for operation in &operations {
+            operation.execute(None).unwrap();
+        }
+        assert!(file.is_symlink());
+        assert_eq!(fs::read_to_string(&dotfile).unwrap(), "content");
+
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_plan_skips_linked_and_fails_foreign_symlinks() {
+        let tmp_dir = TempDir::new().unwrap();
+        let dotfiles_dir = tmp_dir.join("dotfiles");
+        let linked = tmp_dir.join("config").join("linked");
+        let foreign = tmp_dir.join("config").join("foreign");
+        let linked_dotfile = crate::dotfile_path(dotfiles_dir.join("symlinks"), &linked).unwrap();
+        create_missing_parents(&linked).unwrap();
+        create_missing_parents(&linked_dotfile).unwrap();
+        fs::write(&linked_dotfile, "content").unwrap();
+        std::os::unix::fs::symlink(&linked_dotfile, &linked).unwrap();
+        std::os::unix::fs::symlink(tmp_dir.join("elsewhere"), &foreign).unwrap();
+        let register = Register {
+            entries: vec![Entry::new(linked.clone()), Entry::new(foreign.clone())],
+            format: RegisterFormat::Structured,
+        };
+
+        for direction in [SyncDirection::Dotfiles, SyncDirection::Filesystem] {
+            let plans = plan_symlinks(
+                &direction,
+                &dotfiles_dir,
+                &register,
+                &[],
+                &Context {
+                    backups: &BackupSet::new(&tmp_dir.join("backups")),
+                    resolver: &Resolver::default(),
+                    state: &State::default(),
+                    relative_symlinks: false,
+                },
+            )
+            .unwrap();
+            assert_eq!(plans[0].path, linked);
+            assert_eq!(plans[0].operations.as_ref().unwrap(), &Vec::new());
+            assert_eq!(plans[1].path, foreign);
+            assert!(matches!(
+                plans[1].operations,
+                Err(ApplicationError::UntrackedSymlinkedFile(_))
+            ));
+        }
+
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_plan_relative_symlinks() {
+        let tmp_dir = TempDir::new().unwrap();
+        let dotfiles_dir = tmp_dir.join("dotfiles");
+        let relative = tmp_dir.join("config").join("relative");
+        let absolute = tmp_dir.join("config").join("absolute");
+        create_missing_parents(&relative).unwrap();
+        fs::write(&relative, "content").unwrap();
+        fs::write(&absolute, "content").unwrap();
+        let register = Register {
+            entries: vec![
+                Entry::new(relative.clone()),
+                Entry {
+                    relative: Some(false),
+                    ..Entry::new(absolute.clone())
+                },
+            ],
+            format: RegisterFormat::Structured,
+        };
+
+        let operations = flatten(
+            plan_symlinks(
+                &SyncDirection::Dotfiles,
+                &dotfiles_dir,
+                &register,
+                &[],
+                &Context {
+                    backups: &BackupSet::new(&tmp_dir.join("backups")),
+                    resolver: &Resolver::default(),
+                    state: &State::default(),
+                    relative_symlinks: true,
+                },
+            )
+            .unwrap(),
+        );
+        for operation in &operations {
+            operation.execute(None).unwrap();
+        }
+        let base_directory = dotfiles_dir.join("symlinks");
+        let relative_dotfile = crate::dotfile_path(base_directory.clone(), &relative).unwrap();
+        let absolute_dotfile = crate::dotfile_path(base_directory, &absolute).unwrap();
+        assert!(fs::read_link(&relative).unwrap().is_relative());
+        assert!(links_to(&relative, &relative_dotfile));
+        assert_eq!(fs::read_link(&absolute).unwrap(), absolute_dotfile);
+        assert_eq!(fs::read_to_string(&relative).unwrap(), "content");
+
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_plan_stow_links_every_file() {
+        let tmp_dir = TempDir::new().unwrap();
+        let dotfiles_dir = tmp_dir.join("dotfiles");
+        let directory = tmp_dir.join("config");
+        fs::create_dir_all(directory.join("nested")).unwrap();
+        fs::write(directory.join("nested").join("file"), "content").unwrap();
+        let register = Register {
+            entries: vec![Entry {
+                link_mode: Some(LinkMode::Stow),
+                ..Entry::new(directory.clone())
+            }],
+            format: RegisterFormat::Structured,
+        };
+
+        let operations = flatten(
+            plan_symlinks(
+                &SyncDirection::Dotfiles,
+                &dotfiles_dir,
+                &register,
+                &[],
+                &Context {
+                    backups: &BackupSet::new(&tmp_dir.join("backups")),
+                    resolver: &Resolver::default(),
+                    state: &State::default(),
+                    relative_symlinks: false,
+                },
+            )
+            .unwrap(),
+        );
+        for operation in &operations {
+            operation.execute(None).unwrap();
+        }
+        let dotfile = crate::dotfile_path(dotfiles_dir.join("symlinks"), &directory).unwrap();
+        assert!(!directory.is_symlink());
+        assert!(!directory.join("nested").is_symlink());
+        assert_eq!(
+            fs::read_link(directory.join("nested").join("file")).unwrap(),
+            dotfile.join("nested").join("file")
+        );
+
+        /* Files added to the source control are linked on the next sync */
+        fs::create_dir_all(dotfile.join("new")).unwrap();
+        fs::write(dotfile.join("new").join("file"), "content").unwrap();
+        let operations = flatten(
+            plan_symlinks(
+                &SyncDirection::Filesystem,
+                &dotfiles_dir,
+                &register,
+                &[],
+                &Context {
+                    backups: &BackupSet::new(&tmp_dir.join("backups")),
+                    resolver: &Resolver::default(),
+                    state: &State::default(),
+                    relative_symlinks: false,
+                },
+            )
+            .unwrap(),
+        );
+        assert_eq!(
+            operations,
+            vec![
+                Operation::CreateDir {
+                    path: directory.join("new")
+                },
+                Operation::Symlink {
+                    target: dotfile.join("new").join("file"),
+                    link: directory.join("new").join("file")
+                },
+            ]
+        );
+
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_plan_leaves_ignored_files_in_place() {
+        let tmp_dir = TempDir::new().unwrap();
+        let dotfiles_dir = tmp_dir.join("dotfiles");
+        let directory = tmp_dir.join("config");
+        fs::create_dir_all(&dotfiles_dir).unwrap();
+        fs::create_dir_all(&directory).unwrap();
+        fs::write(dotfiles_dir.join(".dotignore"), "*.lock\n").unwrap();
+        fs::write(directory.join("file"), "content").unwrap();
+        fs::write(directory.join("app.lock"), "").unwrap();
+        let register = Register {
+            entries: vec![Entry::new(directory.clone())],
+            format: RegisterFormat::Structured,
+        };
+
+        let operations = flatten(
+            plan_symlinks(
+                &SyncDirection::Dotfiles,
+                &dotfiles_dir,
+                &register,
+                &[],
+                &Context {
+                    backups: &BackupSet::new(&tmp_dir.join("backups")),
+                    resolver: &Resolver::default(),
+                    state: &State::default(),
+                    relative_symlinks: false,
+                },
+            )
+            .unwrap(),
+        );
+        let dotfile = crate::dotfile_path(dotfiles_dir.join("symlinks"), &directory).unwrap();
+        assert_eq!(
+            operations,
+            vec![
+                Operation::MoveToRepo {
+                    from: directory.join("file"),
+                    to: dotfile.join("file")
+                },
+                Operation::Symlink {
+                    target: dotfile.join("file"),
+                    link: directory.join("file")
+                },
+            ]
+        );
+
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_plan_untrack_restores_files() {
+        let tmp_dir = TempDir::new().unwrap();
+        let file = tmp_dir.join("file");
+        let dotfile = tmp_dir.join("repo").join("file");
+        let directory = tmp_dir.join("dir");
+        let dotfile_directory = tmp_dir.join("repo").join("dir");
+        create_missing_parents(&dotfile).unwrap();
+        fs::write(&dotfile, "content").unwrap();
+        std::os::unix::fs::symlink(&dotfile, &file).unwrap();
+        fs::create_dir_all(&directory).unwrap();
+        fs::create_dir_all(&dotfile_directory).unwrap();
+        fs::write(dotfile_directory.join("linked"), "content").unwrap();
+        fs::write(dotfile_directory.join("missing"), "content").unwrap();
+        std::os::unix::fs::symlink(dotfile_directory.join("linked"), directory.join("linked"))
+            .unwrap();
+
+        let operations = plan_untrack(&file, &dotfile).unwrap();
+        assert_eq!(
+            operations,
+            vec![
+                Operation::Unlink {
+                    link: file.clone(),
+                    target: dotfile.clone()
+                },
+                Operation::Restore {
+                    from: dotfile.clone(),
+                    to: file.clone()
+                },
+            ]
+        );
+        let operations = operations
+            .into_iter()
+            .chain(plan_untrack(&directory, &dotfile_directory).unwrap())
+            .collect::<Vec<_>>();
+        for operation in &operations {
+            operation.execute(None).unwrap();
+        }
+        assert!(!file.is_symlink());
+        assert_eq!(fs::read_to_string(&file).unwrap(), "content");
+        assert!(!directory.join("linked").is_symlink());
+        assert!(directory.join("missing").is_file());
+        assert!(!dotfile.exists());
+        assert!(!dotfile_directory.join("linked").exists());
+
+        crate::journal::rollback(&operations).unwrap();
+        assert_eq!(fs::read_link(&file).unwrap(), dotfile);
+        assert!(!directory.join("missing").exists());
+
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_plan_auto_takes_changed_side() {
+        let tmp_dir = TempDir::new().unwrap();
+        let backups = BackupSet::new(&tmp_dir.join("backups"));
+        let file = tmp_dir.join("file");
+        let dotfile = tmp_dir.join("repo").join("file");
+        create_missing_parents(&dotfile).unwrap();
+        fs::write(&file, "local").unwrap();
+        fs::write(&dotfile, "repo").unwrap();
+        let plan = |recorded: &str| {
+            let mut state = State::default();
+            state.synced.insert(
+                file.clone(),
+                state::hash_file(&tmp_dir.join(recorded)).unwrap(),
+            );
+            plan_symlink(
+                &SyncDirection::Auto,
+                &file,
+                dotfile.clone(),
+                &Context {
+                    backups: &backups,
+                    resolver: &Resolver::default(),
+                    state: &state,
+                    relative_symlinks: false,
+                },
+            )
+        };
+        let symlink = Operation::Symlink {
+            target: dotfile.clone(),
+            link: file.clone(),
+        };
+
+        fs::write(tmp_dir.join("unchanged"), "local").unwrap();
+        assert_eq!(
+            plan("unchanged").unwrap(),
+            vec![
+                Operation::Backup {
+                    from: file.clone(),
+                    to: backups.path_for(&file)
+                },
+                symlink.clone(),
+            ]
+        );
+        fs::write(tmp_dir.join("unchanged"), "repo").unwrap();
+        assert_eq!(
+            plan("unchanged").unwrap(),
+            vec![
+                Operation::Backup {
+                    from: dotfile.clone(),
+                    to: backups.path_for(&dotfile)
+                },
+                Operation::MoveToRepo {
+                    from: file.clone(),
+                    to: dotfile.clone()
+                },
+                symlink,
+            ]
+        );
+        fs::write(tmp_dir.join("unchanged"), "original").unwrap();
+        assert!(matches!(
+            plan("unchanged"),
+            Err(ApplicationError::BothSidesChanged(_))
+        ));
+
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_plan_resolves_conflicts() {
+        let tmp_dir = TempDir::new().unwrap();
+        let backups = BackupSet::new(&tmp_dir.join("backups"));
+        let file = tmp_dir.join("file");
+        let dotfile = tmp_dir.join("repo").join("file");
+        create_missing_parents(&dotfile).unwrap();
+        fs::write(&file, "content").unwrap();
+        fs::write(&dotfile, "content").unwrap();
+        let symlink = Operation::Symlink {
+            target: dotfile.clone(),
+            link: file.clone(),
+        };
+
+        let skip = Resolver {
+            strategy: ConflictStrategy::Skip,
+            dry_run: false,
+        };
+        assert_eq!(
+            plan_replace(
+                &file,
+                &dotfile,
+                &Context {
+                    backups: &backups,
+                    resolver: &skip,
+                    state: &State::default(),
+                    relative_symlinks: false,
+                },
+            )
+            .unwrap(),
+            vec![
+                Operation::Discard {
+                    path: file.clone(),
+                    target: dotfile.clone()
+                },
+                symlink.clone(),
+            ]
+        );
+
+        fs::write(&file, "changed").unwrap();
+        assert!(plan_replace(
+            &file,
+            &dotfile,
+            &Context {
+                backups: &backups,
+                resolver: &skip,
+                state: &State::default(),
+                relative_symlinks: false,
+            },
+        )
+        .unwrap()
+        .is_empty());
+        let keep_local = Resolver {
+            strategy: ConflictStrategy::KeepLocal,
+            dry_run: false,
+        };
+        assert_eq!(
+            plan_replace(
+                &file,
+                &dotfile,
+                &Context {
+                    backups: &backups,
+                    resolver: &keep_local,
+                    state: &State::default(),
+                    relative_symlinks: false,
+                },
+            )
+            .unwrap(),
+            vec![
+                Operation::Backup {
+                    from: dotfile.clone(),
+                    to: backups.path_for(&dotfile)
+                },
+                Operation::MoveToRepo {
+                    from: file.clone(),
+                    to: dotfile.clone()
+                },
+                symlink.clone(),
+            ]
+        );
+        let take_repo = Resolver {
+            strategy: ConflictStrategy::TakeRepo,
+            dry_run: false,
+        };
+        assert_eq!(
+            plan_replace(
+                &file,
+                &dotfile,
+                &Context {
+                    backups: &backups,
+                    resolver: &take_repo,
+                    state: &State::default(),
+                    relative_symlinks: false,
+                },
+            )
+            .unwrap(),
+            vec![
+                Operation::Backup {
+                    from: file.clone(),
+                    to: backups.path_for(&file)
+                },
+                symlink,
+            ]
+        );
+
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_plan_copy_detects_local_edits() {
+        let tmp_dir = TempDir::new().unwrap();
+        let backups = BackupSet::new(&tmp_dir.join("backups"));
+        let file = tmp_dir.join("file");
+        let dotfile = tmp_dir.join("repo").join("file");
+        create_missing_parents(&dotfile).unwrap();
+        fs::write(&dotfile, "content").unwrap();
+        let mut state = State::default();
+        let plan = |direction: &SyncDirection, state: &State| {
+            let context = Context {
+                backups: &backups,
+                resolver: &Resolver {
+                    strategy: ConflictStrategy::Skip,
+                    dry_run: false,
+                },
+                state,
+                relative_symlinks: false,
+            };
+            plan_copy(direction, &file, &dotfile, &context).unwrap()
+        };
+
+        let operations = plan(&SyncDirection::Filesystem, &state);
+        assert_eq!(
+            operations,
+            vec![Operation::Copy {
+                from: dotfile.clone(),
+                to: file.clone()
+            }]
+        );
+        operations[0].execute(None).unwrap();
+        state
+            .copies
+            .insert(file.clone(), state::hash_file(&file).unwrap());
+        assert!(plan(&SyncDirection::Filesystem, &state).is_empty());
+
+        /* Local edits are pulled into the source control, but never overwritten silently */
+        fs::write(&file, "edited").unwrap();
+        assert!(plan(&SyncDirection::Filesystem, &state).is_empty());
+        assert_eq!(
+            plan(&SyncDirection::Dotfiles, &state),
+            vec![
+                Operation::Backup {
+                    from: dotfile.clone(),
+                    to: backups.path_for(&dotfile)
+                },
+                Operation::Copy {
+                    from: file.clone(),
+                    to: dotfile.clone()
+                },
+            ]
+        );
+
+        /* Changes within the source control are deployed when the copy is unchanged */
+        state
+            .copies
+            .insert(file.clone(), state::hash_file(&file).unwrap());
+        assert!(plan(&SyncDirection::Dotfiles, &state).is_empty());
+        assert_eq!(
+            plan(&SyncDirection::Filesystem, &state),
+            vec![
+                Operation::Backup {
+                    from: file.clone(),
+                    to: backups.path_for(&file)
+                },
+                Operation::Copy {
+                    from: dotfile.clone(),
+                    to: file.clone()
+                },
+            ]
+        );
+
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_plan_filesystem_skips_missing() {
+        let tmp_dir = TempDir::new().unwrap();
+        let register = Register {
+            entries: vec![Entry::new(tmp_dir.join("missing"))],
+            format: RegisterFormat::Structured,
+        };
+
+        let operations = flatten(
+            plan_symlinks(
+                &SyncDirection::Filesystem,
+                &tmp_dir.join("dotfiles"),
+                &register,
+                &[],
+                &Context {
+                    backups: &BackupSet::new(&tmp_dir.join("backups")),
+                    resolver: &Resolver::default(),
+                    state: &State::default(),
+                    relative_symlinks: false,
+                },
+            )
+            .unwrap(),
+        );
+        assert!(operations.is_empty());
+
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_plan_secret_restores_metadata() {
+        let tmp_dir = TempDir::new().unwrap();
+        let dotfiles_dir = tmp_dir.join("dotfiles");
+        let entry = Entry {
+            permissions: Some("400".to_string()),
+            owner: Some("1000:100".to_string()),
+            ..Entry::new(tmp_dir.join("secret"))
+        };
+        let dotfile = entry.dotfile_path(dotfiles_dir.join("secrets")).unwrap();
+        create_missing_parents(&dotfile).unwrap();
+        fs::write(&dotfile, "encrypted").unwrap();
+        let context = Context {
+            backups: &BackupSet::new(&tmp_dir.join("backups")),
+            resolver: &Resolver::default(),
+            state: &State::default(),
+            relative_symlinks: false,
+        };
+
+        assert_eq!(
+            plan_secret(&SyncDirection::Filesystem, &dotfiles_dir, &entry, &context).unwrap(),
+            vec![
+                Operation::Decrypt {
+                    from: dotfile.clone(),
+                    to: entry.path.clone(),
+                },
+                Operation::SetOwner {
+                    path: entry.path.clone(),
+                    uid: 1000,
+                    gid: Some(100),
+                },
+                Operation::SetMode {
+                    path: entry.path.clone(),
+                    mode: 0o400,
+                },
+            ]
+        );
+        let invalid = Entry {
+            owner: Some("me".to_string()),
+            ..entry
+        };
+        assert!(matches!(
+            plan_secret(
+                &SyncDirection::Filesystem,
+                &dotfiles_dir,
+                &invalid,
+                &context
+            ),
+            Err(ApplicationError::InvalidOwner(_))
+        ));
+
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_plan_secret_skips_unchanged() {
+        let tmp_dir = TempDir::new().unwrap();
+        let dotfiles_dir = tmp_dir.join("dotfiles");
+        let entry = Entry::new(tmp_dir.join("secret"));
+        let dotfile = entry.dotfile_path(dotfiles_dir.join("secrets")).unwrap();
+        create_missing_parents(&dotfile).unwrap();
+        fs::write(&entry.path, "plaintext").unwrap();
+        fs::write(&dotfile, "encrypted").unwrap();
+        let mut state = State::default();
+        state.secrets.insert(
+            entry.path.clone(),
+            SecretHashes {
+                plaintext: state::hash_file(&entry.path).unwrap(),
+                encrypted: state::hash_file(&dotfile).unwrap(),
+            },
+        );
+        let backups = BackupSet::new(&tmp_dir.join("backups"));
+        let resolver = Resolver::default();
+        let plan = |state: &State| {
+            let context = Context {
+                backups: &backups,
+                resolver: &resolver,
+                state,
+                relative_symlinks: false,
+            };
+            plan_secret(&SyncDirection::Dotfiles, &dotfiles_dir, &entry, &context)
+        };
+        let encrypt = |compare| {
+            vec![Operation::Encrypt {
+                from: entry.path.clone(),
+                to: dotfile.clone(),
+                compare,
+            }]
+        };
+
+        assert!(plan(&state).unwrap().is_empty());
+        assert_eq!(plan(&State::default()).unwrap(), encrypt(true));
+        fs::write(&entry.path, "changed").unwrap();
+        assert_eq!(plan(&state).unwrap(), encrypt(false));
+
+        // A newer encrypted copy pulled into the source control is never overwritten
+        fs::write(&dotfile, "pulled").unwrap();
+        assert!(matches!(
+            plan(&state),
+            Err(ApplicationError::BothSidesChanged(_))
+        ));
+        fs::write(&entry.path, "plaintext").unwrap();
+        assert!(matches!(
+            plan(&state),
+            Err(ApplicationError::EncryptedCopyChanged(_))
+        ));
+
+        tmp_dir.close().unwrap();
+    }
+}
@@ -0,0 +1,483 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    create_missing_parents,
+    crypto::{self, Key, Recipient},
+    dotignore::DotIgnore,
+    error::ApplicationError,
+    journal,
+    plan::Operation,
+    register::Register,
+    state::{self, State},
+};
+
+/// Plaintext of a secret within the source control, held in memory while it is re-encrypted.
+pub(crate) struct DecryptedSecret {
+    /// Location of the plaintext on the filesystem keying its recorded hashes
+    pub(crate) path: PathBuf,
+    pub(crate) dotfile: PathBuf,
+    pub(crate) content: Vec<u8>,
+}
+
+impl DecryptedSecret {
+    /// Updates the recorded hashes of a secret which was in sync before its encrypted copy
+    /// changed from `previous`, keeping it in sync.
+    pub(crate) fn record(&self, state: &mut State, previous: &str) -> Result<(), ApplicationError> {
+        if let Some(hashes) = state
+            .secrets
+            .get_mut(&self.path)
+            .filter(|hashes| hashes.encrypted == previous)
+        {
+            hashes.encrypted = state::hash_file(&self.dotfile)?;
+        }
+        Ok(())
+    }
+}
+
+/// Decrypts the secret of every register entry within `base_directory`, including the secrets
+/// of other hosts.
+///
+/// Everything is decrypted before anything is written, so a key which cannot decrypt one of the
+/// secrets leaves the source control untouched. Files within `base_directory` which are not
+/// tracked by the register are left alone.
+pub(crate) fn decrypt_all(
+    register: &Register,
+    base_directory: &Path,
+    key: &Key,
+) -> Result<Vec<DecryptedSecret>, ApplicationError> {
+    let mut decrypted = Vec::new();
+    for entry in &register.entries {
+        let dotfile = entry.dotfile_path(base_directory.to_path_buf())?;
+        if !fs::symlink_metadata(&dotfile).is_ok_and(|metadata| metadata.is_file()) {
+            continue;
+        }
+        decrypted.push(DecryptedSecret {
+            path: entry.path.clone(),
+            content: crypto::decrypt(&dotfile, key)?,
+            dotfile,
+        });
+    }
+    Ok(decrypted)
+}
+
+/// Change of the recipients every secret is encrypted to.
+pub(crate) enum Change {
+    Add(Recipient),
+    Remove(Recipient),
+}
+
+/// Secrets staged for a change of the recipients which has not been applied yet.
+pub(crate) struct PendingChange {
+    change: Change,
+    staged: Staged,
+}
+
+impl PendingChange {
+    /// Stores or removes the changed recipient within `recipients_dir` and replaces the secrets
+    /// within `secrets_dir` by the staged ones, returning the number of re-encrypted secrets.
+    ///
+    /// The recipient is changed back when the secrets could not be replaced.
+    pub(crate) fn apply(
+        self,
+        secrets_dir: &Path,
+        recipients_dir: &Path,
+        journal_path: &Path,
+        state: &mut State,
+    ) -> Result<usize, ApplicationError> {
+        match &self.change {
+            Change::Add(recipient) => {
+                store(recipients_dir, recipient)?;
+            }
+            Change::Remove(recipient) => fs::remove_file(&recipient.path)
+                .map_err(|err| ApplicationError::FailedRemovingFile(recipient.path.clone(), err))?,
+        }
+        let swapped = self.staged.swap(secrets_dir, journal_path, state);
+        /* A journal left behind means some secrets might have been replaced already */
+        if swapped.is_err() && !journal_path.exists() {
+            match &self.change {
+                Change::Add(recipient) => {
+                    let path = recipients_dir.join(format!("{}.asc", recipient.fingerprint()));
+                    fs::remove_file(&path)
+                        .map_err(|err| ApplicationError::FailedRemovingFile(path, err))?;
+                }
+                Change::Remove(recipient) => {
+                    store(recipients_dir, recipient)?;
+                }
+            }
+        }
+        swapped
+    }
+}
+
+/// Changes the recipients of `key` and stages every secret within `secrets_dir` encrypted to
+/// them, leaving the source control untouched until the change is applied.
+///
+/// The secrets are decrypted with the key before its recipients change.
+pub(crate) fn stage_change(
+    change: Change,
+    key: &mut Key,
+    register: &Register,
+    secrets_dir: &Path,
+    staging_dir: &Path,
+) -> Result<PendingChange, ApplicationError> {
+    let decrypted = decrypt_all(register, secrets_dir, key)?;
+    match &change {
+        Change::Add(recipient) => key.recipients.push(recipient.clone()),
+        Change::Remove(recipient) => key
+            .recipients
+            .retain(|other| other.fingerprint() != recipient.fingerprint()),
+    }
+    Ok(PendingChange {
+        staged: stage(decrypted, secrets_dir, staging_dir, key)?,
+        change,
+    })
+}
+
+/// Secrets encrypted again into a staging directory, waiting to replace the secrets within the
+/// source control.
+pub(crate) struct Staged {
+    pub(crate) secrets: Vec<DecryptedSecret>,
+    /// Hash of the encrypted copy replaced by every staged secret
+    previous: Vec<String>,
+    staging_dir: PathBuf,
+}
+
+impl Staged {
+    /// Replaces `secrets_dir` by the staging directory, returning the number of replaced secrets.
+    ///
+    /// Both directories are swapped by renaming them, recorded in the journal so that an
+    /// interrupted swap is rolled back like an interrupted sync. Secrets which were in sync keep
+    /// being in sync.
+    pub(crate) fn swap(
+        self,
+        secrets_dir: &Path,
+        journal_path: &Path,
+        state: &mut State,
+    ) -> Result<usize, ApplicationError> {
+        if fs::symlink_metadata(&self.staging_dir).is_err() {
+            return Ok(0);
+        }
+        let previous_dir = previous_dir(&self.staging_dir);
+        journal::execute(
+            journal_path,
+            &[
+                Operation::Backup {
+                    from: secrets_dir.to_path_buf(),
+                    to: previous_dir.clone(),
+                },
+                Operation::MoveToRepo {
+                    from: self.staging_dir.clone(),
+                    to: secrets_dir.to_path_buf(),
+                },
+            ],
+            None,
+        )?;
+        remove_dir(&previous_dir)?;
+        for (secret, previous) in self.secrets.iter().zip(&self.previous) {
+            secret.record(state, previous)?;
+        }
+        Ok(self.secrets.len())
+    }
+}
+
+/// Copies every file within `secrets_dir` into `staging_dir`, encrypting the decrypted secrets
+/// again to the key and its current recipients.
+///
+/// Every encrypted secret is decrypted again and compared to its plaintext, nothing is staged
+/// unless all of them decrypt to their original content.
+pub(crate) fn stage(
+    secrets: Vec<DecryptedSecret>,
+    secrets_dir: &Path,
+    staging_dir: &Path,
+    key: &Key,
+) -> Result<Staged, ApplicationError> {
+    remove_dir(staging_dir)?;
+    remove_dir(&previous_dir(staging_dir))?;
+    let mut staged = Staged {
+        previous: Vec::new(),
+        secrets,
+        staging_dir: staging_dir.to_path_buf(),
+    };
+    if !secrets_dir.is_dir() {
+        return Ok(staged);
+    }
+    if let Err(err) = stage_files(&mut staged, secrets_dir, key) {
+        remove_dir(staging_dir)?;
+        return Err(err);
+    }
+    Ok(staged)
+}
+
+fn stage_files(staged: &mut Staged, secrets_dir: &Path, key: &Key) -> Result<(), ApplicationError> {
+    fs::create_dir_all(&staged.staging_dir).map_err(|err| {
+        ApplicationError::CouldNotCreateDirectories(staged.staging_dir.clone(), err)
+    })?;
+    let contents = staged
+        .secrets
+        .iter()
+        .map(|secret| (&secret.dotfile, &secret.content))
+        .collect::<BTreeMap<_, _>>();
+    for relative in DotIgnore::default().walk(secrets_dir)?.files {
+        let (from, to) = (
+            secrets_dir.join(&relative),
+            staged.staging_dir.join(relative),
+        );
+        create_missing_parents(&to)?;
+        let Some(content) = contents.get(&from) else {
+            copy_file(&from, &to)?;
+            continue;
+        };
+        crypto::encrypt(content, &to, key)?;
+        if crypto::decrypt(&to, key)? != **content {
+            return Err(ApplicationError::ReencryptionVerificationFailed(from));
+        }
+    }
+    for secret in &staged.secrets {
+        staged.previous.push(state::hash_file(&secret.dotfile)?);
+    }
+    Ok(())
+}
+
+/// Copies a file not encrypted again into the staging directory, keeping symlinks as they are.
+fn copy_file(from: &Path, to: &Path) -> Result<(), ApplicationError> {
+    let copied = match fs::read_link(from) {
+        Ok(target) => std::os::unix::fs::symlink(target, to),
+        Err(_) => fs::copy(from, to).map(|_| ()),
+    };
+    copied.map_err(|err| ApplicationError::FailedRenamingFile {
+        err,
+        from: from.to_path_buf(),
+        to: to.to_path_buf(),
+    })
+}
+
+/// Location the replaced secrets are moved to while swapping in the staging directory.
+fn previous_dir(staging_dir: &Path) -> PathBuf {
+    let mut name = staging_dir.as_os_str().to_owned();
+    name.push(".old");
+    PathBuf::from(name)
+}
+
+fn remove_dir(directory: &Path) -> Result<(), ApplicationError> {
+    if fs::symlink_metadata(directory).is_err() {
+        return Ok(());
+    }
+    fs::remove_dir_all(directory)
+        .map_err(|err| ApplicationError::FailedRemovingFile(directory.to_path_buf(), err))
+}
+
+/// Stores the public key of a recipient within `directory`, named after its fingerprint.
+pub(crate) fn store(directory: &Path, recipient: &Recipient) -> Result<PathBuf, ApplicationError> {
+    let path = directory.join(format!("{}.asc", recipient.fingerprint()));
+    let content = recipient
+        .key
+        .to_armored_string(None)
+        .map_err(|err| ApplicationError::PGPWriterError(path.clone(), err))?;
+    create_missing_parents(&path)?;
+    fs::write(&path, content)
+        .map_err(|err| ApplicationError::FailedWritingToFile(path.clone(), err))?;
+    Ok(path)
+}
+
+/// Position of the recipient whose fingerprint ends with `id`, e.g. its full fingerprint or its
+/// key id.
+pub(crate) fn find(recipients: &[Recipient], id: &str) -> Result<usize, ApplicationError> {
+    let id = id.replace(' ', "").to_uppercase();
+    let mut matching = recipients
+        .iter()
+        .enumerate()
+        .filter(|(_, recipient)| !id.is_empty() && recipient.fingerprint().ends_with(&id))
+        .map(|(position, _)| position);
+    match (matching.next(), matching.next()) {
+        (Some(position), None) => Ok(position),
+        (Some(_), Some(_)) => Err(ApplicationError::AmbiguousRecipient(id)),
+        (None, _) => Err(ApplicationError::RecipientNotFound(id)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        crypto::test_key,
+        register::{Entry, RegisterFormat},
+        state::SecretHashes,
+    };
+    use assert_fs::TempDir;
+
+    fn recipient(key: &Key) -> Recipient {
+        Recipient {
+            key: key.public_key().unwrap(),
+            path: PathBuf::new(),
+        }
+    }
+
+    #[test]
+    fn test_find() {
+        let tmp_dir = TempDir::new().unwrap();
+        let key = test_key(&tmp_dir.join("key"));
+        let other = test_key(&tmp_dir.join("other"));
+        let recipients = vec![recipient(&key), recipient(&other)];
+        let fingerprint = other.fingerprint();
+
+        assert_eq!(find(&recipients, &fingerprint).unwrap(), 1);
+        assert_eq!(
+            find(&recipients, &fingerprint[24..].to_lowercase()).unwrap(),
+            1
+        );
+        assert!(matches!(
+            find(&recipients, ""),
+            Err(ApplicationError::RecipientNotFound(_))
+        ));
+        assert!(matches!(
+            find(&recipients, "XYZ"),
+            Err(ApplicationError::RecipientNotFound(_))
+        ));
+        let duplicated = vec![recipient(&other), recipient(&other)];
+        assert!(matches!(
+            find(&duplicated, &fingerprint),
+            Err(ApplicationError::AmbiguousRecipient(_))
+        ));
+
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_store() {
+        let tmp_dir = TempDir::new().unwrap();
+        let key = test_key(&tmp_dir.join("key"));
+        let recipients_dir = tmp_dir.join("cfg").join("recipients");
+
+        let path = store(&recipients_dir, &recipient(&key)).unwrap();
+        assert_eq!(
+            path,
+            recipients_dir.join(format!("{}.asc", key.fingerprint()))
+        );
+        let loaded = crypto::load_recipients(&recipients_dir).unwrap();
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[0].fingerprint(), key.fingerprint());
+        assert_eq!(loaded[0].path, path);
+
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_stage_and_swap() {
+        let tmp_dir = TempDir::new().unwrap();
+        let secrets_dir = tmp_dir.join("secrets");
+        let staging_dir = tmp_dir.join(".staging");
+        let journal_path = tmp_dir.join("journal");
+        let key = test_key(&tmp_dir.join("key"));
+        let netrc = secrets_dir.join("netrc");
+        create_missing_parents(&netrc).unwrap();
+        crypto::encrypt(b"netrc", &netrc, &key).unwrap();
+        fs::write(secrets_dir.join(".gitkeep"), "").unwrap();
+        let register = Register {
+            entries: vec![
+                Entry {
+                    repo_path: Some(PathBuf::from("netrc")),
+                    ..Entry::new(tmp_dir.join(".netrc"))
+                },
+                Entry {
+                    repo_path: Some(PathBuf::from("unsynced")),
+                    ..Entry::new(tmp_dir.join(".unsynced"))
+                },
+            ],
+            format: RegisterFormat::Structured,
+        };
+        let mut state = State::default();
+        state.secrets.insert(
+            tmp_dir.join(".netrc"),
+            SecretHashes {
+                plaintext: String::new(),
+                encrypted: state::hash_file(&netrc).unwrap(),
+            },
+        );
+
+        let decrypted = decrypt_all(&register, &secrets_dir, &key).unwrap();
+        assert_eq!(decrypted.len(), 1);
+        let staged = stage(decrypted, &secrets_dir, &staging_dir, &key).unwrap();
+        assert!(staging_dir.join(".gitkeep").exists());
+        let encrypted = fs::read(&netrc).unwrap();
+        assert_eq!(
+            staged
+                .swap(&secrets_dir, &journal_path, &mut state)
+                .unwrap(),
+            1
+        );
+
+        assert_ne!(fs::read(&netrc).unwrap(), encrypted);
+        assert_eq!(crypto::decrypt(&netrc, &key).unwrap(), b"netrc");
+        assert!(secrets_dir.join(".gitkeep").exists());
+        assert!(!staging_dir.exists());
+        assert!(!journal_path.exists());
+        assert_eq!(
+            state.secrets[&tmp_dir.join(".netrc")].encrypted,
+            state::hash_file(&netrc).unwrap()
+        );
+
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_add_and_remove_recipient() {
+        let tmp_dir = TempDir::new().unwrap();
+        let secrets_dir = tmp_dir.join("secrets");
+        let recipients_dir = tmp_dir.join("recipients");
+        let staging_dir = tmp_dir.join(".staging");
+        let journal_path = tmp_dir.join("journal");
+        let mut key = test_key(&tmp_dir.join("key"));
+        let other = test_key(&tmp_dir.join("other"));
+        let netrc = secrets_dir.join("netrc");
+        create_missing_parents(&netrc).unwrap();
+        crypto::encrypt(b"netrc", &netrc, &key).unwrap();
+        let register = Register {
+            entries: vec![Entry {
+                repo_path: Some(PathBuf::from("netrc")),
+                ..Entry::new(tmp_dir.join(".netrc"))
+            }],
+            format: RegisterFormat::Structured,
+        };
+        let mut state = State::default();
+
+        let pending = stage_change(
+            Change::Add(recipient(&other)),
+            &mut key,
+            &register,
+            &secrets_dir,
+            &staging_dir,
+        )
+        .unwrap();
+        assert!(crypto::load_recipients(&recipients_dir).unwrap().is_empty());
+        assert!(crypto::decrypt(&netrc, &other).is_err());
+        let count = pending
+            .apply(&secrets_dir, &recipients_dir, &journal_path, &mut state)
+            .unwrap();
+        assert_eq!(count, 1);
+        assert_eq!(crypto::decrypt(&netrc, &other).unwrap(), b"netrc");
+        assert_eq!(crypto::decrypt(&netrc, &key).unwrap(), b"netrc");
+
+        key.recipients = crypto::load_recipients(&recipients_dir).unwrap();
+        assert_eq!(key.recipients.len(), 1);
+        let removed = key.recipients[find(&key.recipients, &other.fingerprint()).unwrap()].clone();
+        stage_change(
+            Change::Remove(removed),
+            &mut key,
+            &register,
+            &secrets_dir,
+            &staging_dir,
+        )
+        .unwrap()
+        .apply(&secrets_dir, &recipients_dir, &journal_path, &mut state)
+        .unwrap();
+        assert!(crypto::load_recipients(&recipients_dir).unwrap().is_empty());
+        assert!(crypto::decrypt(&netrc, &other).is_err());
+        assert_eq!(crypto::decrypt(&netrc, &key).unwrap(), b"netrc");
+
+        tmp_dir.close().unwrap();
+    }
+}
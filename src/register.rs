@@ -0,0 +1,700 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, BufWriter, ErrorKind, Write},
+    os::unix::fs::MetadataExt,
+    path::{Component, Path, PathBuf},
+};
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::{dotfile_path, error::ApplicationError, plan::Operation};
+
+/// How a tracked entry is deployed onto the filesystem.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum LinkMode {
+    /// The file or directory is replaced by a single symlink into the source control
+    #[default]
+    Symlink,
+    /// Directories are recreated on the filesystem and every file within is symlinked on its own
+    Stow,
+    /// The file is copied onto the filesystem, for applications which replace or ignore symlinks
+    Copy,
+}
+
+/// A single tracked file together with its options.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Entry {
+    /// Location of the tracked file on the filesystem
+    pub(crate) path: PathBuf,
+    /// Location within the source control relative to `symlinks/` or `secrets/`, derived from
+    /// `path` when omitted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) repo_path: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) link_mode: Option<LinkMode>,
+    /// Hostnames the entry is synced on, every host when empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) hosts: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) tags: Vec<String>,
+    /// Octal file mode applied to the file on the filesystem, e.g. "600"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) permissions: Option<String>,
+    /// Numeric owner applied to a decrypted secret, e.g. "1000" or "1000:1000" including the group
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) owner: Option<String>,
+    /// Link relative to the location of the symlink, overriding the `relative_symlinks` option
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) relative: Option<bool>,
+}
+
+impl Entry {
+    pub(crate) fn new(path: PathBuf) -> Self {
+        Entry {
+            path,
+            ..Default::default()
+        }
+    }
+
+    /// Location of the entry within `base_directory` of the source control.
+    pub(crate) fn dotfile_path(
+        &self,
+        base_directory: PathBuf,
+    ) -> Result<PathBuf, ApplicationError> {
+        match &self.repo_path {
+            Some(repo_path) => Ok(base_directory.join(repo_path)),
+            None => dotfile_path(base_directory, &self.path),
+        }
+    }
+
+    pub(crate) fn link_mode(&self) -> LinkMode {
+        self.link_mode.unwrap_or_default()
+    }
+
+    /// Parses the octal permissions of the entry.
+    pub(crate) fn mode(&self) -> Result<Option<u32>, ApplicationError> {
+        self.permissions
+            .as_ref()
+            .map(|permissions| {
+                u32::from_str_radix(permissions.trim_start_matches("0o"), 8)
+                    .map_err(|_| ApplicationError::InvalidPermissions(permissions.clone()))
+            })
+            .transpose()
+    }
+
+    /// Parses the numeric user id and optional group id of the entry.
+    pub(crate) fn owner(&self) -> Result<Option<(u32, Option<u32>)>, ApplicationError> {
+        self.owner
+            .as_ref()
+            .map(|owner| {
+                let invalid = || ApplicationError::InvalidOwner(owner.clone());
+                let (uid, gid) = match owner.split_once(':') {
+                    Some((uid, gid)) => (uid, Some(gid.parse().map_err(|_| invalid())?)),
+                    None => (owner.as_str(), None),
+                };
+                Ok((uid.parse().map_err(|_| invalid())?, gid))
+            })
+            .transpose()
+    }
+
+    /// Whether the entry is synced on `hostname` with the given tag filter.
+    ///
+    /// An empty tag filter matches every entry.
+    pub(crate) fn is_active(&self, hostname: Option<&str>, tags: &[String]) -> bool {
+        let on_host = self.hosts.is_empty()
+            || hostname.is_some_and(|hostname| self.hosts.iter().any(|host| host == hostname));
+        let tagged = tags.is_empty() || self.tags.iter().any(|tag| tags.contains(tag));
+        on_host && tagged
+    }
+}
+
+/// On-disk format of a register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum RegisterFormat {
+    /// One absolute path per line, without any options
+    Legacy,
+    /// TOML file with an `[[entry]]` table per tracked file
+    Structured,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct RegisterFile {
+    #[serde(default, rename = "entry")]
+    entries: Vec<Entry>,
+}
+
+/// List of tracked files, e.g. `cfg/symlinks` or `cfg/secrets`.
+///
+/// The structured register is stored next to the legacy one with a `.toml` extension and takes
+/// precedence when both exist. Paths within the home directory are stored relative to `~`, making
+/// the register portable between machines, and are expanded when the register is loaded.
+#[derive(Debug)]
+pub(crate) struct Register {
+    pub(crate) entries: Vec<Entry>,
+    pub(crate) format: RegisterFormat,
+}
+
+impl Register {
+    /// Reads the register at `cfg_path`, treating a missing register as one without entries.
+    ///
+    /// New registers use the structured format.
+    pub(crate) fn load(cfg_path: &Path) -> Result<Self, ApplicationError> {
+        let structured_path = structured_path(cfg_path);
+        match fs::read_to_string(&structured_path) {
+            Ok(content) => {
+                let register: RegisterFile = toml::from_str(&content).map_err(|err| {
+                    ApplicationError::InvalidRegister(structured_path.clone(), err.to_string())
+                })?;
+                /* The register may come from a pull, and a sync moves and links files to wherever
+                 * the location within the source control points */
+                if let Some(repo_path) = register.entries.iter().find_map(|entry| {
+                    entry.repo_path.as_ref().filter(|repo_path| {
+                        repo_path.is_absolute()
+                            || repo_path
+                                .components()
+                                .any(|component| component == Component::ParentDir)
+                    })
+                }) {
+                    return Err(ApplicationError::InvalidRegister(
+                        structured_path,
+                        format!(
+                            "repo_path '{}' must be relative and stay within the source control",
+                            repo_path.display()
+                        ),
+                    ));
+                }
+                return Ok(Register {
+                    entries: expand_entries(register.entries)?,
+                    format: RegisterFormat::Structured,
+                });
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => return Err(ApplicationError::CouldNotOpenFile(structured_path, err)),
+        }
+
+        let cfg_file = match File::open(cfg_path) {
+            Ok(cfg_file) => cfg_file,
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                return Ok(Register {
+                    entries: Vec::new(),
+                    format: RegisterFormat::Structured,
+                })
+            }
+            Err(err) => {
+                return Err(ApplicationError::CouldNotOpenFile(
+                    cfg_path.to_path_buf(),
+                    err,
+                ))
+            }
+        };
+        let entries = BufReader::new(cfg_file)
+            .lines()
+            .filter(|line| line.as_ref().map_or(true, |line| !line.trim().is_empty()))
+            .map(|line| {
+                line.map(|line| Entry::new(PathBuf::from(line)))
+                    .map_err(|_| ApplicationError::ConfigFileReadError(cfg_path.to_path_buf()))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Register {
+            entries: expand_entries(entries)?,
+            format: RegisterFormat::Legacy,
+        })
+    }
+
+    /// Writes the register back to disk in its format.
+    pub(crate) fn save(&self, cfg_path: &Path) -> Result<(), ApplicationError> {
+        let home = home_dir()?;
+        let entries = self
+            .entries
+            .iter()
+            .map(|entry| Entry {
+                path: contract_home(&entry.path, &home),
+                ..entry.clone()
+            })
+            .collect::<Vec<_>>();
+        let (out_path, content) = match self.format {
+            RegisterFormat::Structured => {
+                let register = RegisterFile { entries };
+                let content = toml::to_string(&register).map_err(|err| {
+                    ApplicationError::InvalidRegister(cfg_path.to_path_buf(), err.to_string())
+                })?;
+                (structured_path(cfg_path), content)
+            }
+            RegisterFormat::Legacy => {
+                let mut content = String::new();
+                for entry in &entries {
+                    if entry != &Entry::new(entry.path.clone()) {
+                        return Err(ApplicationError::LegacyRegisterOptions(
+                            cfg_path.to_path_buf(),
+                        ));
+                    }
+                    let path = entry
+                        .path
+                        .to_str()
+                        .ok_or(ApplicationError::PathConversionError(entry.path.clone()))?;
+                    content.push_str(path);
+                    content.push('\n');
+                }
+                (cfg_path.to_path_buf(), content)
+            }
+        };
+
+        let tmp_path = out_path.with_extension("tmp");
+        {
+            let tmp_file = OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(true)
+                .open(&tmp_path)
+                .map_err(|err| ApplicationError::CouldNotOpenFile(tmp_path.clone(), err))?;
+            let mut writer = BufWriter::new(tmp_file);
+            writer
+                .write_all(content.as_bytes())
+                .and_then(|_| writer.flush())
+                .map_err(|err| ApplicationError::FailedWritingToFile(tmp_path.clone(), err))?;
+        }
+        fs::rename(&tmp_path, &out_path).map_err(|err| ApplicationError::FailedRenamingFile {
+            err,
+            from: tmp_path,
+            to: out_path,
+        })
+    }
+
+    /// Entries synced on this machine with the given tag filter.
+    pub(crate) fn active_entries<'a>(
+        &'a self,
+        tags: &'a [String],
+    ) -> impl Iterator<Item = &'a Entry> + 'a {
+        let hostname = hostname();
+        self.entries
+            .iter()
+            .filter(move |entry| entry.is_active(hostname.as_deref(), tags))
+    }
+
+    /// Position of the entry tracking exactly `path`, compared as normalized paths.
+    pub(crate) fn position(&self, path: &Path) -> Option<usize> {
+        let path = normalize_path(path);
+        self.entries
+            .iter()
+            .position(|entry| normalize_path(&entry.path) == path)
+    }
+
+    /// Entries nested inside of `path` or containing it, without the entry tracking it exactly.
+    pub(crate) fn overlapping<'a>(&'a self, path: &Path) -> impl Iterator<Item = &'a Entry> + 'a {
+        let path = normalize_path(path);
+        self.entries.iter().filter(move |entry| {
+            let other = normalize_path(&entry.path);
+            other != path && (other.starts_with(&path) || path.starts_with(&other))
+        })
+    }
+
+    /// Records the permissions, and the owner when `with_owner` is set, of every secret
+    /// encrypted by the executed operations, so they are restored when it is decrypted.
+    ///
+    /// Legacy registers cannot store them and are left untouched. Returns whether anything has
+    /// changed.
+    pub(crate) fn record_permissions(
+        &mut self,
+        executed: &[Operation],
+        with_owner: bool,
+    ) -> Result<bool, ApplicationError> {
+        if self.format == RegisterFormat::Legacy {
+            return Ok(false);
+        }
+        let mut changed = false;
+        for operation in executed {
+            let Operation::Encrypt { from, .. } = operation else {
+                continue;
+            };
+            let Some(position) = self.position(from) else {
+                continue;
+            };
+            let metadata = fs::metadata(from)
+                .map_err(|err| ApplicationError::CouldNotOpenFile(from.clone(), err))?;
+            let entry = &mut self.entries[position];
+            let permissions = Some(format!("{:o}", metadata.mode() & 0o7777));
+            let owner = match with_owner {
+                true => Some(format!("{}:{}", metadata.uid(), metadata.gid())),
+                false => entry.owner.clone(),
+            };
+            if entry.permissions != permissions || entry.owner != owner {
+                entry.permissions = permissions;
+                entry.owner = owner;
+                changed = true;
+            }
+        }
+        Ok(changed)
+    }
+}
+
+/// Resolves `.` and `..` components of a path without touching the filesystem, keeping symlinks
+/// intact.
+pub(crate) fn normalize_path(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+/// Changes made to a register by [`migrate`].
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Migration {
+    /// The line based register has been converted into the structured format
+    pub(crate) converted: bool,
+    /// Number of entries moved from the old home directory into the current one
+    pub(crate) rewritten: usize,
+}
+
+/// Converts a legacy register into the structured format and stores every path within the home
+/// directory relative to `~`.
+///
+/// Entries within `old_home`, the home directory of the machine the register was written on,
+/// are moved into the current home directory. Returns `None` when the register does not exist.
+pub(crate) fn migrate(
+    cfg_path: &Path,
+    old_home: Option<&Path>,
+) -> Result<Option<Migration>, ApplicationError> {
+    let exists = |path: &Path| {
+        path.try_exists()
+            .map_err(ApplicationError::FailedCheckingExistanceOfFile)
+    };
+    if !exists(cfg_path)? && !exists(&structured_path(cfg_path))? {
+        return Ok(None);
+    }
+
+    let mut register = Register::load(cfg_path)?;
+    let converted = register.format == RegisterFormat::Legacy;
+    let mut rewritten = 0;
+    if let Some(old_home) = old_home {
+        let home = home_dir()?;
+        for entry in &mut register.entries {
+            if let Ok(relative) = entry.path.strip_prefix(old_home) {
+                entry.path = home.join(relative);
+                rewritten += 1;
+            }
+        }
+    }
+
+    register.format = RegisterFormat::Structured;
+    register.save(cfg_path)?;
+    if converted {
+        fs::remove_file(cfg_path)
+            .map_err(|err| ApplicationError::FailedRemovingFile(cfg_path.to_path_buf(), err))?;
+    }
+    Ok(Some(Migration {
+        converted,
+        rewritten,
+    }))
+}
+
+pub(crate) fn structured_path(cfg_path: &Path) -> PathBuf {
+    cfg_path.with_extension("toml")
+}
+
+fn home_dir() -> Result<PathBuf, ApplicationError> {
+    Ok(PathBuf::from(
+        std::env::var("HOME").map_err(|_| ApplicationError::UndedfinedHomeVariable)?,
+    ))
+}
+
+/// Replaces the home directory prefix of `path` with `~`.
+fn contract_home(path: &Path, home: &Path) -> PathBuf {
+    let canonical_home = fs::canonicalize(home).ok();
+    let relative = [Some(home), canonical_home.as_deref()]
+        .into_iter()
+        .flatten()
+        .find_map(|home| path.strip_prefix(home).ok());
+    match relative {
+        Some(relative) => Path::new("~").join(relative),
+        None => path.to_path_buf(),
+    }
+}
+
+/// Replaces a leading `~` of `path` with the home directory.
+fn expand_home(path: &Path, home: &Path) -> PathBuf {
+    match path.strip_prefix("~") {
+        Ok(relative) => home.join(relative),
+        Err(_) => path.to_path_buf(),
+    }
+}
+
+fn expand_entries(entries: Vec<Entry>) -> Result<Vec<Entry>, ApplicationError> {
+    let home = home_dir()?;
+    Ok(entries
+        .into_iter()
+        .map(|entry| Entry {
+            path: expand_home(&entry.path, &home),
+            ..entry
+        })
+        .collect())
+}
+
+/// Name of this machine, matched against the hosts of entries.
+pub(crate) fn hostname() -> Option<String> {
+    fs::read_to_string("/proc/sys/kernel/hostname")
+        .or_else(|_| fs::read_to_string("/etc/hostname"))
+        .ok()
+        .map(|hostname| hostname.trim().to_string())
+        .or_else(|| std::env::var("HOSTNAME").ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_fs::TempDir;
+
+    #[test]
+    fn test_load_legacy_register() {
+        let tmp_dir = TempDir::new().unwrap();
+        let cfg_path = tmp_dir.join("symlinks");
+        fs::write(&cfg_path, "/home/user/.vimrc\n\n/home/user/.zshrc\n").unwrap();
+
+        let register = Register::load(&cfg_path).unwrap();
+        assert_eq!(register.format, RegisterFormat::Legacy);
+        assert_eq!(
+            register.entries,
+            vec![
+                Entry::new(PathBuf::from("/home/user/.vimrc")),
+                Entry::new(PathBuf::from("/home/user/.zshrc")),
+            ]
+        );
+
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_structured_round_trip() {
+        let tmp_dir = TempDir::new().unwrap();
+        let cfg_path = tmp_dir.join("secrets");
+        let register = Register {
+            entries: vec![Entry {
+                path: PathBuf::from("/home/user/.ssh/id_ed25519"),
+                repo_path: Some(PathBuf::from("ssh/id_ed25519")),
+                hosts: vec!["laptop".to_string()],
+                tags: vec!["ssh".to_string()],
+                permissions: Some("600".to_string()),
+                owner: Some("1000:1000".to_string()),
+                ..Default::default()
+            }],
+            format: RegisterFormat::Structured,
+        };
+        register.save(&cfg_path).unwrap();
+        assert!(!cfg_path.exists());
+
+        let loaded = Register::load(&cfg_path).unwrap();
+        assert_eq!(loaded.format, RegisterFormat::Structured);
+        assert_eq!(loaded.entries, register.entries);
+        assert_eq!(loaded.entries[0].mode().unwrap(), Some(0o600));
+        assert_eq!(loaded.entries[0].owner().unwrap(), Some((1000, Some(1000))));
+
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_repo_path_stays_within_source_control() {
+        let tmp_dir = TempDir::new().unwrap();
+        let cfg_path = tmp_dir.join("symlinks");
+        for repo_path in ["/etc/profile", "../outside", "nested/../../outside"] {
+            fs::write(
+                structured_path(&cfg_path),
+                format!("[[entry]]\npath = \"/home/user/.profile\"\nrepo_path = \"{repo_path}\"\n"),
+            )
+            .unwrap();
+            let err = Register::load(&cfg_path).unwrap_err();
+            assert!(matches!(err, ApplicationError::InvalidRegister(..)));
+            assert_eq!(err.exit_code(), crate::error::EXIT_CONFIG);
+        }
+
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_record_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp_dir = TempDir::new().unwrap();
+        let file = tmp_dir.join("secret");
+        fs::write(&file, "secret").unwrap();
+        fs::set_permissions(&file, fs::Permissions::from_mode(0o640)).unwrap();
+        let executed = vec![Operation::Encrypt {
+            from: file.clone(),
+            to: tmp_dir.join("dotfiles").join("secret"),
+            compare: false,
+        }];
+        let mut register = Register {
+            entries: vec![Entry::new(file.clone())],
+            format: RegisterFormat::Structured,
+        };
+
+        assert!(register.record_permissions(&executed, false).unwrap());
+        assert_eq!(register.entries[0].mode().unwrap(), Some(0o640));
+        assert_eq!(register.entries[0].owner, None);
+        assert!(!register.record_permissions(&executed, false).unwrap());
+        assert!(register.record_permissions(&executed, true).unwrap());
+        let metadata = fs::metadata(&file).unwrap();
+        assert_eq!(
+            register.entries[0].owner().unwrap(),
+            Some((metadata.uid(), Some(metadata.gid())))
+        );
+
+        let mut legacy = Register {
+            entries: vec![Entry::new(file)],
+            format: RegisterFormat::Legacy,
+        };
+        assert!(!legacy.record_permissions(&executed, true).unwrap());
+        assert_eq!(legacy.entries[0].permissions, None);
+
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_legacy_register_rejects_options() {
+        let tmp_dir = TempDir::new().unwrap();
+        let cfg_path = tmp_dir.join("symlinks");
+        let register = Register {
+            entries: vec![Entry {
+                path: PathBuf::from("/home/user/.vimrc"),
+                tags: vec!["vim".to_string()],
+                ..Default::default()
+            }],
+            format: RegisterFormat::Legacy,
+        };
+        assert!(matches!(
+            register.save(&cfg_path),
+            Err(ApplicationError::LegacyRegisterOptions(_))
+        ));
+
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_migrate_register() {
+        let tmp_dir = TempDir::new().unwrap();
+        let cfg_path = tmp_dir.join("symlinks");
+        fs::write(&cfg_path, "/home/user/.vimrc\n").unwrap();
+
+        assert_eq!(
+            migrate(&cfg_path, None).unwrap(),
+            Some(Migration {
+                converted: true,
+                rewritten: 0
+            })
+        );
+        assert!(!cfg_path.exists());
+        let register = Register::load(&cfg_path).unwrap();
+        assert_eq!(register.format, RegisterFormat::Structured);
+        assert_eq!(
+            register.entries,
+            vec![Entry::new(PathBuf::from("/home/user/.vimrc"))]
+        );
+        assert_eq!(
+            migrate(&cfg_path, None).unwrap(),
+            Some(Migration {
+                converted: false,
+                rewritten: 0
+            })
+        );
+        assert_eq!(migrate(&tmp_dir.join("secrets"), None).unwrap(), None);
+
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_home_relative_paths() {
+        let home = PathBuf::from(std::env::var("HOME").unwrap());
+        let file = home.join(".config").join("nvim");
+        assert_eq!(contract_home(&file, &home), PathBuf::from("~/.config/nvim"));
+        assert_eq!(
+            contract_home(Path::new("/etc/hosts"), &home),
+            PathBuf::from("/etc/hosts")
+        );
+        assert_eq!(expand_home(Path::new("~/.config/nvim"), &home), file);
+        assert_eq!(
+            expand_home(Path::new("/etc/hosts"), &home),
+            PathBuf::from("/etc/hosts")
+        );
+    }
+
+    #[test]
+    fn test_migrate_old_home() {
+        let tmp_dir = TempDir::new().unwrap();
+        let home = PathBuf::from(std::env::var("HOME").unwrap());
+        let cfg_path = tmp_dir.join("symlinks");
+        fs::write(&cfg_path, "/home/alice/.vimrc\n/etc/hosts\n").unwrap();
+
+        assert_eq!(
+            migrate(&cfg_path, Some(Path::new("/home/alice"))).unwrap(),
+            Some(Migration {
+                converted: true,
+                rewritten: 1
+            })
+        );
+        assert!(fs::read_to_string(structured_path(&cfg_path))
+            .unwrap()
+            .contains("\"~/.vimrc\""));
+        assert_eq!(
+            Register::load(&cfg_path).unwrap().entries,
+            vec![
+                Entry::new(home.join(".vimrc")),
+                Entry::new(PathBuf::from("/etc/hosts")),
+            ]
+        );
+
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_exact_path_matching() {
+        let register = Register {
+            entries: vec![
+                Entry::new(PathBuf::from("/home/me/.bashrc")),
+                Entry::new(PathBuf::from("/home/me/.config/nvim")),
+            ],
+            format: RegisterFormat::Structured,
+        };
+
+        assert_eq!(register.position(Path::new("/home/me/.bash")), None);
+        assert_eq!(register.position(Path::new("/home/me/./.bashrc")), Some(0));
+        assert_eq!(
+            register.position(Path::new("/home/me/.config/nvim/../nvim")),
+            Some(1)
+        );
+        assert_eq!(register.overlapping(Path::new("/home/me/.bash")).count(), 0);
+        assert_eq!(
+            register
+                .overlapping(Path::new("/home/me/.config"))
+                .collect::<Vec<_>>(),
+            vec![&register.entries[1]]
+        );
+        assert_eq!(
+            register
+                .overlapping(Path::new("/home/me/.config/nvim/init.lua"))
+                .collect::<Vec<_>>(),
+            vec![&register.entries[1]]
+        );
+    }
+
+    #[test]
+    fn test_entry_filters() {
+        let entry = Entry {
+            path: PathBuf::from("/home/user/.vimrc"),
+            hosts: vec!["laptop".to_string()],
+            tags: vec!["editor".to_string()],
+            ..Default::default()
+        };
+        assert!(entry.is_active(Some("laptop"), &[]));
+        assert!(!entry.is_active(Some("server"), &[]));
+        assert!(!entry.is_active(None, &[]));
+        assert!(entry.is_active(Some("laptop"), &["editor".to_string()]));
+        assert!(!entry.is_active(Some("laptop"), &["shell".to_string()]));
+    }
+}
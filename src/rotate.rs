@@ -0,0 +1,199 @@
+use std::{
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    backup::format_timestamp,
+    crypto::{Key, Recipient},
+    error::ApplicationError,
+    recipients::{self, Staged},
+    register::Register,
+    state::State,
+};
+
+/// Location the new key is generated at while rotating the key at `key_path`, e.g.
+/// `secret.key.new`.
+pub(crate) fn new_key_path(key_path: &Path) -> PathBuf {
+    with_suffix(key_path, ".new")
+}
+
+/// Secrets staged for the new key, together with the old key if it was listed as a recipient.
+pub(crate) struct Rotation {
+    staged: Staged,
+    replaced: Option<Recipient>,
+}
+
+/// Re-encrypts every secret within `secrets_dir` from the `old` key to the `new` one, together
+/// with the recipients of `new`, into `staging_dir`.
+///
+/// Every staged secret is decrypted again with the new key, the secrets within the source control
+/// are left untouched until [`replace`]. An old key listed as a recipient is left out.
+pub(crate) fn stage(
+    register: &Register,
+    secrets_dir: &Path,
+    staging_dir: &Path,
+    old: &Key,
+    new: &mut Key,
+) -> Result<Rotation, ApplicationError> {
+    let replaced = new
+        .recipients
+        .iter()
+        .position(|recipient| recipient.fingerprint() == old.fingerprint())
+        .map(|position| new.recipients.remove(position));
+    let decrypted = recipients::decrypt_all(register, secrets_dir, old)?;
+    Ok(Rotation {
+        staged: recipients::stage(decrypted, secrets_dir, staging_dir, new)?,
+        replaced,
+    })
+}
+
+/// Replaces the secrets within `secrets_dir` by the staged ones and an old key listed as a
+/// recipient by the new one, returning the number of re-encrypted secrets.
+pub(crate) fn replace(
+    rotation: Rotation,
+    secrets_dir: &Path,
+    recipients_dir: &Path,
+    journal_path: &Path,
+    new: &mut Key,
+    state: &mut State,
+) -> Result<usize, ApplicationError> {
+    let count = rotation.staged.swap(secrets_dir, journal_path, state)?;
+    if let Some(replaced) = rotation.replaced {
+        fs::remove_file(&replaced.path)
+            .map_err(|err| ApplicationError::FailedRemovingFile(replaced.path.clone(), err))?;
+        let mut recipient = Recipient {
+            key: new.public_key()?,
+            path: PathBuf::new(),
+        };
+        recipient.path = recipients::store(recipients_dir, &recipient)?;
+        new.recipients.push(recipient);
+    }
+    Ok(count)
+}
+
+/// Archives the key at `key_path` next to it, named after the current time, and moves the new
+/// key into its place so the configuration keeps pointing at the current key.
+///
+/// Returns the location of the archived key.
+pub(crate) fn swap_keys(key_path: &Path, new_path: &Path) -> Result<PathBuf, ApplicationError> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let archived = with_suffix(key_path, &format!(".{}.old", format_timestamp(now)));
+    for (from, to) in [(key_path, archived.as_path()), (new_path, key_path)] {
+        fs::rename(from, to).map_err(|err| ApplicationError::FailedRenamingFile {
+            err,
+            from: from.to_path_buf(),
+            to: to.to_path_buf(),
+        })?;
+    }
+    Ok(archived)
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().map(OsString::from).unwrap_or_default();
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        create_missing_parents,
+        crypto::{self, test_key},
+        register::{Entry, RegisterFormat},
+        state::{self, SecretHashes},
+    };
+    use assert_fs::TempDir;
+
+    #[test]
+    fn test_reencrypt_with_new_key() {
+        let tmp_dir = TempDir::new().unwrap();
+        let secrets_dir = tmp_dir.join("secrets");
+        let recipients_dir = tmp_dir.join("recipients");
+        let (key_path, new_path) = (tmp_dir.join("key"), new_key_path(&tmp_dir.join("key")));
+        assert_eq!(new_path, tmp_dir.join("key.new"));
+        let old = test_key(&key_path);
+        let mut new = test_key(&new_path);
+        new.recipients.push(Recipient {
+            key: old.public_key().unwrap(),
+            path: PathBuf::new(),
+        });
+        new.recipients[0].path = recipients::store(&recipients_dir, &new.recipients[0]).unwrap();
+
+        let netrc = secrets_dir.join("netrc");
+        let other_host = secrets_dir.join("other").join("netrc");
+        create_missing_parents(&other_host).unwrap();
+        crypto::encrypt(b"tracked", &netrc, &old).unwrap();
+        crypto::encrypt(b"\x00other host", &other_host, &old).unwrap();
+        let register = Register {
+            entries: vec![
+                Entry {
+                    repo_path: Some(PathBuf::from("netrc")),
+                    ..Entry::new(tmp_dir.join(".netrc"))
+                },
+                Entry {
+                    repo_path: Some(PathBuf::from("other/netrc")),
+                    hosts: vec!["other".to_string()],
+                    ..Entry::new(tmp_dir.join("other"))
+                },
+            ],
+            format: RegisterFormat::Structured,
+        };
+        let mut state = State::default();
+        state.secrets.insert(
+            tmp_dir.join(".netrc"),
+            SecretHashes {
+                plaintext: String::new(),
+                encrypted: state::hash_file(&netrc).unwrap(),
+            },
+        );
+
+        let staging_dir = tmp_dir.join(".rotate-key");
+        let journal_path = tmp_dir.join("journal");
+        let rotation = stage(&register, &secrets_dir, &staging_dir, &old, &mut new).unwrap();
+        assert_eq!(crypto::decrypt(&netrc, &old).unwrap(), b"tracked");
+        assert!(staging_dir.join("netrc").exists());
+        let count = replace(
+            rotation,
+            &secrets_dir,
+            &recipients_dir,
+            &journal_path,
+            &mut new,
+            &mut state,
+        )
+        .unwrap();
+        assert_eq!(count, 2);
+        assert!(!staging_dir.exists());
+        assert!(!tmp_dir.join(".rotate-key.old").exists());
+        assert!(!journal_path.exists());
+        assert_eq!(crypto::decrypt(&netrc, &new).unwrap(), b"tracked");
+        assert_eq!(
+            crypto::decrypt(&other_host, &new).unwrap(),
+            b"\x00other host"
+        );
+        assert!(crypto::decrypt(&netrc, &old).is_err());
+        assert_eq!(
+            state.secrets[&tmp_dir.join(".netrc")].encrypted,
+            state::hash_file(&netrc).unwrap()
+        );
+
+        let recipients = crypto::load_recipients(&recipients_dir).unwrap();
+        assert_eq!(recipients.len(), 1);
+        assert_eq!(recipients[0].fingerprint(), new.fingerprint());
+
+        fs::write(&key_path, "old").unwrap();
+        fs::write(&new_path, "new").unwrap();
+        let archived = swap_keys(&key_path, &new_path).unwrap();
+        assert_eq!(fs::read_to_string(&key_path).unwrap(), "new");
+        assert_eq!(fs::read_to_string(&archived).unwrap(), "old");
+        assert!(!new_path.exists());
+
+        tmp_dir.close().unwrap();
+    }
+}
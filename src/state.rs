@@ -0,0 +1,299 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    create_missing_parents,
+    dotignore::DotIgnore,
+    error::ApplicationError,
+    layout::resolves_to,
+    plan::Operation,
+    register::{LinkMode, Register},
+};
+
+/// Directory holding the state of this machine, `$XDG_STATE_HOME/dotfiles` or
+/// `~/.local/state/dotfiles` when the variable is unset.
+pub(crate) fn state_dir() -> Result<PathBuf, ApplicationError> {
+    let state_home = match std::env::var("XDG_STATE_HOME") {
+        Ok(state_home) if Path::new(&state_home).is_absolute() => PathBuf::from(state_home),
+        _ => PathBuf::from(
+            std::env::var("HOME").map_err(|_| ApplicationError::UndedfinedHomeVariable)?,
+        )
+        .join(".local")
+        .join("state"),
+    };
+    Ok(state_home.join("dotfiles"))
+}
+
+/// Location of the state file of this machine.
+pub(crate) fn state_path() -> Result<PathBuf, ApplicationError> {
+    Ok(state_dir()?.join("state.toml"))
+}
+
+/// Hashes of the files last synced on this machine, stored outside of the source control since
+/// every machine deploys at its own pace.
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct State {
+    /// Hash of the content of files deployed as a copy, keyed by their path on the filesystem
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) copies: BTreeMap<PathBuf, String>,
+    /// Hash of the content of linked files and directories, keyed by their path on the
+    /// filesystem
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) synced: BTreeMap<PathBuf, String>,
+    /// Hashes of both sides of every secret, keyed by the path of the plaintext
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) secrets: BTreeMap<PathBuf, SecretHashes>,
+}
+
+/// Hashes of a secret as they were when it was last encrypted or decrypted.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct SecretHashes {
+    pub(crate) plaintext: String,
+    pub(crate) encrypted: String,
+}
+
+impl SecretHashes {
+    /// Whether the plaintext `file` and the encrypted `dotfile`, both existing, have changed since
+    /// the hashes were recorded.
+    pub(crate) fn changes(
+        &self,
+        file: &Path,
+        dotfile: &Path,
+    ) -> Result<(bool, bool), ApplicationError> {
+        Ok((
+            self.plaintext != hash_file(file)?,
+            self.encrypted != hash_file(dotfile)?,
+        ))
+    }
+}
+
+impl State {
+    pub(crate) fn load(path: &Path) -> Result<Self, ApplicationError> {
+        match fs::read_to_string(path) {
+            Ok(content) => toml::from_str(&content)
+                .map_err(|err| ApplicationError::InvalidState(path.to_path_buf(), err.to_string())),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(State::default()),
+            Err(err) => Err(ApplicationError::CouldNotOpenFile(path.to_path_buf(), err)),
+        }
+    }
+
+    /// Records the hash of every copied entry whose file on the filesystem is identical to its
+    /// copy within `base_directory`.
+    ///
+    /// Returns whether anything has changed.
+    pub(crate) fn record_copies(
+        &mut self,
+        register: &Register,
+        base_directory: &Path,
+        tags: &[String],
+    ) -> Result<bool, ApplicationError> {
+        let mut changed = false;
+        for entry in register
+            .active_entries(tags)
+            .filter(|entry| entry.link_mode() == LinkMode::Copy)
+        {
+            let dotfile = entry.dotfile_path(base_directory.to_path_buf())?;
+            if !entry.path.is_file() || !dotfile.is_file() {
+                continue;
+            }
+            let hash = hash_file(&entry.path)?;
+            if hash == hash_file(&dotfile)? && self.copies.get(&entry.path) != Some(&hash) {
+                self.copies.insert(entry.path.clone(), hash);
+                changed = true;
+            }
+        }
+        Ok(changed)
+    }
+
+    /// Records the content of every linked entry, linked file by file for directories containing
+    /// files which are not tracked.
+    ///
+    /// Returns whether anything has changed.
+    pub(crate) fn record_links(
+        &mut self,
+        register: &Register,
+        base_directory: &Path,
+        tags: &[String],
+        dotignore: &DotIgnore,
+    ) -> Result<bool, ApplicationError> {
+        let mut linked = Vec::new();
+        for entry in register
+            .active_entries(tags)
+            .filter(|entry| entry.link_mode() != LinkMode::Copy)
+        {
+            let dotfile = entry.dotfile_path(base_directory.to_path_buf())?;
+            if resolves_to(&entry.path, &dotfile) {
+                linked.push((entry.path.clone(), dotfile));
+            } else if entry.path.is_dir() && dotfile.is_dir() {
+                for relative in dotignore.walk(&dotfile)?.files {
+                    let (file, dotfile) = (entry.path.join(&relative), dotfile.join(&relative));
+                    if resolves_to(&file, &dotfile) {
+                        linked.push((file, dotfile));
+                    }
+                }
+            }
+        }
+
+        let mut changed = false;
+        for (file, dotfile) in linked {
+            if fs::symlink_metadata(&dotfile).is_err() {
+                continue;
+            }
+            let hash = hash_tree(&dotfile)?;
+            if self.synced.get(&file) != Some(&hash) {
+                self.synced.insert(file, hash);
+                changed = true;
+            }
+        }
+        Ok(changed)
+    }
+
+    /// Records the hashes of both sides of every secret encrypted or decrypted by the executed
+    /// operations.
+    ///
+    /// Returns whether anything has changed.
+    pub(crate) fn record_secrets(
+        &mut self,
+        executed: &[Operation],
+    ) -> Result<bool, ApplicationError> {
+        let mut changed = false;
+        for operation in executed {
+            let (plaintext, encrypted) = match operation {
+                Operation::Encrypt { from, to, .. } => (from, to),
+                Operation::Decrypt { from, to } => (to, from),
+                _ => continue,
+            };
+            let hashes = SecretHashes {
+                plaintext: hash_file(plaintext)?,
+                encrypted: hash_file(encrypted)?,
+            };
+            if self.secrets.get(plaintext) != Some(&hashes) {
+                self.secrets.insert(plaintext.clone(), hashes);
+                changed = true;
+            }
+        }
+        Ok(changed)
+    }
+
+    /// Drops every hash recorded for `path` or a file within it, so that a file tracked again
+    /// later is not mistaken for having been synced.
+    ///
+    /// Returns whether anything has changed.
+    pub(crate) fn forget(&mut self, path: &Path) -> bool {
+        let before = self.copies.len() + self.synced.len() + self.secrets.len();
+        self.copies.retain(|file, _| !file.starts_with(path));
+        self.synced.retain(|file, _| !file.starts_with(path));
+        self.secrets.retain(|file, _| !file.starts_with(path));
+        before != self.copies.len() + self.synced.len() + self.secrets.len()
+    }
+
+    pub(crate) fn save(&self, path: &Path) -> Result<(), ApplicationError> {
+        let content = toml::to_string(self)
+            .map_err(|err| ApplicationError::InvalidState(path.to_path_buf(), err.to_string()))?;
+        create_missing_parents(path)?;
+        fs::write(path, content)
+            .map_err(|err| ApplicationError::FailedWritingToFile(path.to_path_buf(), err))
+    }
+}
+
+/// Hex encoded SHA-256 hash of the content of a file.
+pub(crate) fn hash_file(path: &Path) -> Result<String, ApplicationError> {
+    let content = fs::read(path)
+        .map_err(|err| ApplicationError::CouldNotOpenFile(path.to_path_buf(), err))?;
+    Ok(format!("{:x}", Sha256::digest(content)))
+}
+
+/// Hash of the content of a file, or of every file within a directory together with its path
+/// relative to the directory.
+pub(crate) fn hash_tree(path: &Path) -> Result<String, ApplicationError> {
+    if !fs::metadata(path)
+        .map_err(|err| ApplicationError::CouldNotOpenFile(path.to_path_buf(), err))?
+        .is_dir()
+    {
+        return hash_file(path);
+    }
+    let mut hasher = Sha256::new();
+    for relative in DotIgnore::default().walk(path)?.files {
+        hasher.update(relative.as_os_str().as_encoded_bytes());
+        hasher.update([0]);
+        hasher.update(hash_file(&path.join(&relative))?);
+        hasher.update([b'\n']);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_fs::TempDir;
+
+    #[test]
+    fn test_state_round_trip() {
+        let tmp_dir = TempDir::new().unwrap();
+        let path = tmp_dir.join("state.toml");
+        let file = tmp_dir.join("file");
+        fs::write(&file, "content").unwrap();
+
+        assert_eq!(State::load(&path).unwrap(), State::default());
+        let mut state = State::default();
+        state.copies.insert(file.clone(), hash_file(&file).unwrap());
+        state.save(&path).unwrap();
+        assert_eq!(State::load(&path).unwrap(), state);
+        assert_eq!(
+            state.copies[&file],
+            "ed7002b439e9ac845f22357d822bac1444730fbdb6016d3ec9432297b9ec9f73"
+        );
+
+        let directory = tmp_dir.join("directory");
+        fs::create_dir(&directory).unwrap();
+        fs::write(directory.join("file"), "content").unwrap();
+        let hash = hash_tree(&directory).unwrap();
+        assert_ne!(hash, hash_tree(&file).unwrap());
+        fs::write(directory.join("file"), "changed").unwrap();
+        assert_ne!(hash_tree(&directory).unwrap(), hash);
+
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_forget() {
+        let mut state = State::default();
+        let hashes = SecretHashes {
+            plaintext: "plaintext".to_string(),
+            encrypted: "encrypted".to_string(),
+        };
+        state
+            .copies
+            .insert(PathBuf::from("/home/me/.gitconfig"), "copy".to_string());
+        state
+            .synced
+            .insert(PathBuf::from("/home/me/.config/app/a"), "a".to_string());
+        state
+            .synced
+            .insert(PathBuf::from("/home/me/.config/app/b"), "b".to_string());
+        state
+            .synced
+            .insert(PathBuf::from("/home/me/.config/apps"), "apps".to_string());
+        state
+            .secrets
+            .insert(PathBuf::from("/home/me/.netrc"), hashes.clone());
+
+        assert!(state.forget(Path::new("/home/me/.netrc")));
+        assert!(state.secrets.is_empty());
+        assert!(state.forget(Path::new("/home/me/.config/app")));
+        assert_eq!(
+            state.synced.keys().collect::<Vec<_>>(),
+            [Path::new("/home/me/.config/apps")]
+        );
+        assert!(state.forget(Path::new("/home/me/.gitconfig")));
+        assert!(state.copies.is_empty());
+        assert!(!state.forget(Path::new("/home/me/.gitconfig")));
+    }
+}
@@ -0,0 +1,419 @@
+use std::{
+    fmt, fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    cli::FileType,
+    dotignore::DotIgnore,
+    error::ApplicationError,
+    layout::resolves_to,
+    plan::contains_ignored,
+    register::{LinkMode, Register},
+    state::{hash_file, state_path, SecretHashes, State},
+};
+
+/// State of a single tracked entry compared to its location within the source control.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum EntryState {
+    /// Symlink pointing to the file within the source control
+    Linked,
+    /// Secret whose plaintext and encrypted copy are unchanged since it was last synced
+    Encrypted,
+    /// Nothing exists at the tracked location on the filesystem
+    MissingOnDisk,
+    /// Symlink pointing into the source control, but the file within it is missing
+    MissingInRepo,
+    /// A real file which has not been moved into the source control yet
+    NotMoved,
+    /// Symlink pointing somewhere other than the source control
+    ForeignSymlink(PathBuf),
+    /// Secret where the plaintext has been modified since it was last encrypted
+    PlaintextNewer,
+    /// Secret without an encrypted copy within the source control
+    EncryptedMissing,
+    /// Copy identical to the file within the source control
+    Copied,
+    /// Copy edited on the filesystem since it was last synced
+    LocallyModified,
+    /// Copy or secret unchanged on the filesystem since it was last synced, but the file within
+    /// the source control has changed
+    RepoModified,
+}
+
+impl EntryState {
+    pub(crate) fn in_sync(&self) -> bool {
+        matches!(
+            self,
+            EntryState::Linked | EntryState::Encrypted | EntryState::Copied
+        )
+    }
+}
+
+impl fmt::Display for EntryState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            EntryState::Linked => "linked",
+            EntryState::Encrypted => "encrypted",
+            EntryState::MissingOnDisk => "missing on disk",
+            EntryState::MissingInRepo => "missing in repo",
+            EntryState::NotMoved => "not moved",
+            EntryState::ForeignSymlink(_) => "foreign symlink",
+            EntryState::PlaintextNewer => "plaintext newer",
+            EntryState::EncryptedMissing => "encrypted missing",
+            EntryState::Copied => "copied",
+            EntryState::LocallyModified => "locally modified",
+            EntryState::RepoModified => "repo modified",
+        })
+    }
+}
+
+/// Classifies a tracked config file against its location within the source control.
+pub(crate) fn config_state(file: &Path, dotfile: &Path) -> Result<EntryState, ApplicationError> {
+    let metadata = match fs::symlink_metadata(file) {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(EntryState::MissingOnDisk),
+        Err(err) => return Err(ApplicationError::CouldNotOpenFile(file.to_path_buf(), err)),
+    };
+    if !metadata.is_symlink() {
+        return Ok(EntryState::NotMoved);
+    }
+
+    let target = fs::read_link(file)
+        .map_err(|err| ApplicationError::CouldNotOpenFile(file.to_path_buf(), err))?;
+    if !resolves_to(file, dotfile) {
+        return Ok(EntryState::ForeignSymlink(target));
+    }
+    if !dotfile
+        .try_exists()
+        .map_err(ApplicationError::FailedCheckingExistanceOfFile)?
+    {
+        return Ok(EntryState::MissingInRepo);
+    }
+    Ok(EntryState::Linked)
+}
+
+/// Classifies a directory tracked file by file against its location within the source control.
+///
+/// The directory is in sync when every file within the source control is linked, otherwise the
+/// state of the first file which is not is reported.
+pub(crate) fn stow_state(
+    directory: &Path,
+    dotfile: &Path,
+    dotignore: &DotIgnore,
+) -> Result<EntryState, ApplicationError> {
+    match fs::symlink_metadata(directory) {
+        Ok(metadata) if metadata.is_dir() => {}
+        Ok(_) => return config_state(directory, dotfile),
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(EntryState::MissingOnDisk),
+        Err(err) => {
+            return Err(ApplicationError::CouldNotOpenFile(
+                directory.to_path_buf(),
+                err,
+            ))
+        }
+    }
+    if !dotfile.is_dir() {
+        return Ok(EntryState::NotMoved);
+    }
+    for relative in dotignore.walk(dotfile)?.files {
+        let state = config_state(&directory.join(&relative), &dotfile.join(&relative))?;
+        if !state.in_sync() {
+            return Ok(state);
+        }
+    }
+    Ok(EntryState::Linked)
+}
+
+/// Classifies a file deployed as a copy against the file within the source control, using the
+/// hash recorded when it was last synced.
+pub(crate) fn copy_state(
+    file: &Path,
+    dotfile: &Path,
+    recorded: Option<&String>,
+) -> Result<EntryState, ApplicationError> {
+    if fs::symlink_metadata(file).is_err() {
+        return Ok(EntryState::MissingOnDisk);
+    }
+    if !dotfile.is_file() {
+        return Ok(EntryState::MissingInRepo);
+    }
+    if !file.is_file() {
+        return Ok(EntryState::NotMoved);
+    }
+    let hash = hash_file(file)?;
+    if hash == hash_file(dotfile)? {
+        return Ok(EntryState::Copied);
+    }
+    match recorded == Some(&hash) {
+        true => Ok(EntryState::RepoModified),
+        false => Ok(EntryState::LocallyModified),
+    }
+}
+
+/// Classifies a tracked secret against its encrypted copy within the source control, using the
+/// hashes recorded when it was last synced.
+///
+/// Secrets never synced on this machine are compared by modification time instead, since an
+/// encrypted copy left untouched by a sync keeps its time.
+pub(crate) fn secret_state(
+    file: &Path,
+    dotfile: &Path,
+    recorded: Option<&SecretHashes>,
+) -> Result<EntryState, ApplicationError> {
+    let plain_modified = match fs::metadata(file) {
+        Ok(metadata) => metadata
+            .modified()
+            .map_err(|err| ApplicationError::CouldNotOpenFile(file.to_path_buf(), err))?,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(EntryState::MissingOnDisk),
+        Err(err) => return Err(ApplicationError::CouldNotOpenFile(file.to_path_buf(), err)),
+    };
+    let encrypted_modified = match fs::metadata(dotfile) {
+        Ok(metadata) => metadata
+            .modified()
+            .map_err(|err| ApplicationError::CouldNotOpenFile(dotfile.to_path_buf(), err))?,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(EntryState::EncryptedMissing),
+        Err(err) => {
+            return Err(ApplicationError::CouldNotOpenFile(
+                dotfile.to_path_buf(),
+                err,
+            ))
+        }
+    };
+    if let Some(recorded) = recorded {
+        return Ok(match recorded.changes(file, dotfile)? {
+            (false, false) => EntryState::Encrypted,
+            (false, true) => EntryState::RepoModified,
+            (true, _) => EntryState::PlaintextNewer,
+        });
+    }
+    if plain_modified > encrypted_modified {
+        return Ok(EntryState::PlaintextNewer);
+    }
+    Ok(EntryState::Encrypted)
+}
+
+/// Prints the state of every entry within both registers.
+///
+/// Returns `true` when every entry is in sync with the source control.
+pub(crate) fn status(
+    dotfiles_dir: &Path,
+    symlinks_cfg_path: &Path,
+    secrets_cfg_path: &Path,
+    tags: &[String],
+) -> Result<bool, ApplicationError> {
+    let dotignore = DotIgnore::load(dotfiles_dir)?;
+    let state = State::load(&state_path()?)?;
+    let mut rows = Vec::new();
+    for entry in Register::load(symlinks_cfg_path)?.active_entries(tags) {
+        let dotfile = entry.dotfile_path(dotfiles_dir.join("symlinks"))?;
+        let entry_state = match entry.link_mode() {
+            LinkMode::Symlink if !contains_ignored(&dotignore, &entry.path)? => {
+                config_state(&entry.path, &dotfile)?
+            }
+            LinkMode::Symlink | LinkMode::Stow => stow_state(&entry.path, &dotfile, &dotignore)?,
+            LinkMode::Copy => copy_state(&entry.path, &dotfile, state.copies.get(&entry.path))?,
+        };
+        rows.push((FileType::Config, entry_state, entry.path.clone()));
+    }
+    for entry in Register::load(secrets_cfg_path)?.active_entries(tags) {
+        let dotfile = entry.dotfile_path(dotfiles_dir.join("secrets"))?;
+        let entry_state = secret_state(&entry.path, &dotfile, state.secrets.get(&entry.path))?;
+        rows.push((FileType::Secret, entry_state, entry.path.clone()));
+    }
+
+    let width = rows
+        .iter()
+        .map(|(_, state, _)| state.to_string().len())
+        .chain(["STATE".len()])
+        .max()
+        .unwrap_or_default();
+    println!("{:width$}  {:6}  PATH", "STATE", "TYPE");
+    for (file_type, state, file) in &rows {
+        let file_type = match file_type {
+            FileType::Config => "config",
+            FileType::Secret => "secret",
+        };
+        match state {
+            EntryState::ForeignSymlink(target) => println!(
+                "{state:width$}  {file_type:6}  {} -> {}",
+                file.display(),
+                target.display()
+            ),
+            _ => println!("{state:width$}  {file_type:6}  {}", file.display()),
+        }
+    }
+
+    Ok(rows.iter().all(|(_, state, _)| state.in_sync()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        backup::BackupSet,
+        cli::SyncDirection,
+        conflict::Resolver,
+        crypto, journal,
+        plan::{self, Context},
+        register::{Entry, RegisterFormat},
+    };
+    use assert_fs::TempDir;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn test_config_states() {
+        let tmp_dir = TempDir::new().unwrap();
+        let dotfile = tmp_dir.join("repo-file");
+        let file = tmp_dir.join("file");
+
+        assert_eq!(
+            config_state(&file, &dotfile).unwrap(),
+            EntryState::MissingOnDisk
+        );
+
+        fs::write(&file, "content").unwrap();
+        assert_eq!(config_state(&file, &dotfile).unwrap(), EntryState::NotMoved);
+
+        fs::remove_file(&file).unwrap();
+        std::os::unix::fs::symlink(&dotfile, &file).unwrap();
+        assert_eq!(
+            config_state(&file, &dotfile).unwrap(),
+            EntryState::MissingInRepo
+        );
+
+        fs::write(&dotfile, "content").unwrap();
+        assert_eq!(config_state(&file, &dotfile).unwrap(), EntryState::Linked);
+
+        fs::remove_file(&file).unwrap();
+        std::os::unix::fs::symlink("repo-file", &file).unwrap();
+        assert_eq!(config_state(&file, &dotfile).unwrap(), EntryState::Linked);
+
+        let elsewhere = tmp_dir.join("elsewhere");
+        fs::remove_file(&file).unwrap();
+        std::os::unix::fs::symlink(&elsewhere, &file).unwrap();
+        assert_eq!(
+            config_state(&file, &dotfile).unwrap(),
+            EntryState::ForeignSymlink(elsewhere)
+        );
+
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_stow_states() {
+        let tmp_dir = TempDir::new().unwrap();
+        let dotfile = tmp_dir.join("repo-dir");
+        let directory = tmp_dir.join("dir");
+
+        assert_eq!(
+            stow_state(&directory, &dotfile, &DotIgnore::default()).unwrap(),
+            EntryState::MissingOnDisk
+        );
+
+        fs::create_dir_all(directory.join("nested")).unwrap();
+        fs::create_dir_all(dotfile.join("nested")).unwrap();
+        fs::write(dotfile.join("nested").join("file"), "content").unwrap();
+        assert_eq!(
+            stow_state(&directory, &dotfile, &DotIgnore::default()).unwrap(),
+            EntryState::MissingOnDisk
+        );
+
+        std::os::unix::fs::symlink(
+            dotfile.join("nested").join("file"),
+            directory.join("nested").join("file"),
+        )
+        .unwrap();
+        fs::write(directory.join("untracked"), "content").unwrap();
+        assert_eq!(
+            stow_state(&directory, &dotfile, &DotIgnore::default()).unwrap(),
+            EntryState::Linked
+        );
+
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_secret_states() {
+        let tmp_dir = TempDir::new().unwrap();
+        let dotfile = tmp_dir.join("secret.asc");
+        let file = tmp_dir.join("secret");
+
+        assert_eq!(
+            secret_state(&file, &dotfile, None).unwrap(),
+            EntryState::MissingOnDisk
+        );
+
+        fs::write(&file, "plain").unwrap();
+        assert_eq!(
+            secret_state(&file, &dotfile, None).unwrap(),
+            EntryState::EncryptedMissing
+        );
+
+        fs::write(&dotfile, "encrypted").unwrap();
+        assert_eq!(
+            secret_state(&file, &dotfile, None).unwrap(),
+            EntryState::Encrypted
+        );
+
+        let later = SystemTime::now() + Duration::from_secs(60);
+        fs::File::options()
+            .write(true)
+            .open(&file)
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+        assert_eq!(
+            secret_state(&file, &dotfile, None).unwrap(),
+            EntryState::PlaintextNewer
+        );
+
+        tmp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_secret_state_after_syncs() {
+        let tmp_dir = TempDir::new().unwrap();
+        let dotfiles_dir = tmp_dir.join("dotfiles");
+        let key = crypto::test_key(&tmp_dir.join("key"));
+        let register = Register {
+            entries: vec![Entry::new(tmp_dir.join("secret"))],
+            format: RegisterFormat::Structured,
+        };
+        let file = register.entries[0].path.clone();
+        let dotfile = register.entries[0]
+            .dotfile_path(dotfiles_dir.join("secrets"))
+            .unwrap();
+        let backups = BackupSet::new(&tmp_dir.join("backups"));
+        let mut state = State::default();
+        let sync = |direction: SyncDirection, state: &mut State| {
+            let context = Context {
+                backups: &backups,
+                resolver: &Resolver::default(),
+                state,
+                relative_symlinks: false,
+            };
+            let plans = plan::plan_secrets(&direction, &dotfiles_dir, &register, &[], &context);
+            let (executed, _) =
+                journal::execute_plans(&tmp_dir.join("journal"), &plans, Some(&key)).unwrap();
+            state.record_secrets(&executed).unwrap();
+        };
+        let status = |state: &State| secret_state(&file, &dotfile, state.secrets.get(&file));
+
+        fs::write(&file, "secret").unwrap();
+        sync(SyncDirection::Dotfiles, &mut state);
+        fs::remove_file(&file).unwrap();
+        sync(SyncDirection::Filesystem, &mut state);
+        assert_eq!(status(&state).unwrap(), EntryState::Encrypted);
+        sync(SyncDirection::Dotfiles, &mut state);
+        assert_eq!(status(&state).unwrap(), EntryState::Encrypted);
+
+        fs::write(&file, "changed").unwrap();
+        assert_eq!(status(&state).unwrap(), EntryState::PlaintextNewer);
+        sync(SyncDirection::Dotfiles, &mut state);
+        assert_eq!(status(&state).unwrap(), EntryState::Encrypted);
+
+        tmp_dir.close().unwrap();
+    }
+}
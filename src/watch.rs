@@ -0,0 +1,238 @@
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+    sync::mpsc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use notify_debouncer_mini::{new_debouncer, notify::RecursiveMode};
+
+use crate::{
+    backup::{self, format_timestamp, BackupSet},
+    cli::SyncDirection,
+    conflict::Resolver,
+    crypto::Key,
+    error::ApplicationError,
+    journal,
+    plan::{self, Context},
+    register::{LinkMode, Register},
+    state::{self, State},
+};
+
+/// Time to wait for further changes of a file before syncing it, so an editor saving a file in
+/// several steps only triggers a single sync.
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Entries synced by `watch`: every secret and every config file deployed as a copy, since
+/// neither is shared with the source control the way a symlink is.
+#[derive(Debug)]
+pub(crate) struct Watched {
+    pub(crate) secrets: Register,
+    pub(crate) copies: Register,
+}
+
+impl Watched {
+    /// Active entries of both registers which are watched.
+    pub(crate) fn new(symlinks: &Register, secrets: &Register, tags: &[String]) -> Self {
+        Watched {
+            secrets: Register {
+                entries: secrets.active_entries(tags).cloned().collect(),
+                format: secrets.format,
+            },
+            copies: Register {
+                entries: symlinks
+                    .active_entries(tags)
+                    .filter(|entry| entry.link_mode() == LinkMode::Copy)
+                    .cloned()
+                    .collect(),
+                format: symlinks.format,
+            },
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.secrets.entries.is_empty() && self.copies.entries.is_empty()
+    }
+
+    fn paths(&self) -> impl Iterator<Item = &Path> {
+        self.secrets
+            .entries
+            .iter()
+            .chain(&self.copies.entries)
+            .map(|entry| entry.path.as_path())
+    }
+
+    /// Watched entries whose file is among the changed paths.
+    fn changed(&self, paths: &BTreeSet<PathBuf>) -> Watched {
+        let filter = |register: &Register| Register {
+            entries: register
+                .entries
+                .iter()
+                .filter(|entry| paths.contains(&entry.path))
+                .cloned()
+                .collect(),
+            format: register.format,
+        };
+        Watched {
+            secrets: filter(&self.secrets),
+            copies: filter(&self.copies),
+        }
+    }
+}
+
+/// Syncs every watched entry towards the dotfiles whenever its file changes, until interrupted.
+///
+/// The directories containing the files are watched rather than the files themselves, since
+/// editors commonly save by replacing the file.
+pub(crate) fn watch(
+    dotfiles_dir: &Path,
+    watched: &Watched,
+    key: Option<&Key>,
+    journal_path: &Path,
+    preserve_owner: bool,
+) -> Result<(), ApplicationError> {
+    let (sender, receiver) = mpsc::channel();
+    let mut debouncer = new_debouncer(DEBOUNCE, sender)
+        .map_err(|err| ApplicationError::FailedWatching(dotfiles_dir.to_path_buf(), err))?;
+    let directories = watched
+        .paths()
+        .filter_map(Path::parent)
+        .collect::<BTreeSet<_>>();
+    for directory in directories {
+        if !directory.is_dir() {
+            log(&format!(
+                "'{}' does not exist, files within it are not watched",
+                directory.display()
+            ));
+            continue;
+        }
+        debouncer
+            .watcher()
+            .watch(directory, RecursiveMode::NonRecursive)
+            .map_err(|err| ApplicationError::FailedWatching(directory.to_path_buf(), err))?;
+    }
+    log(&format!(
+        "Watching {} file(s) for changes",
+        watched.paths().count()
+    ));
+
+    for result in receiver {
+        let events = match result {
+            Ok(events) => events,
+            Err(err) => {
+                log(&format!("Failed receiving changes: {err}"));
+                continue;
+            }
+        };
+        let paths = events
+            .into_iter()
+            .map(|event| event.path)
+            .collect::<BTreeSet<_>>();
+        let changed = watched.changed(&paths);
+        if changed.is_empty() {
+            continue;
+        }
+        if let Err(err) = sync(dotfiles_dir, &changed, key, journal_path, preserve_owner) {
+            log(&err.to_string());
+        }
+    }
+    Ok(())
+}
+
+/// Syncs the changed entries towards the dotfiles, the same way a sync does.
+fn sync(
+    dotfiles_dir: &Path,
+    changed: &Watched,
+    key: Option<&Key>,
+    journal_path: &Path,
+    preserve_owner: bool,
+) -> Result<(), ApplicationError> {
+    let backups = BackupSet::new(&backup::store_dir()?);
+    let resolver = Resolver::default();
+    let state_path = state::state_path()?;
+    let mut state = State::load(&state_path)?;
+    let context = Context {
+        backups: &backups,
+        resolver: &resolver,
+        state: &state,
+        relative_symlinks: false,
+    };
+    let mut plans = plan::plan_symlinks(
+        &SyncDirection::Dotfiles,
+        dotfiles_dir,
+        &changed.copies,
+        &[],
+        &context,
+    )?;
+    plans.extend(plan::plan_secrets(
+        &SyncDirection::Dotfiles,
+        dotfiles_dir,
+        &changed.secrets,
+        &[],
+        &context,
+    ));
+
+    let (executed, summary) = journal::execute_plans(journal_path, &plans, key)?;
+    for operation in &executed {
+        log(&operation.to_string());
+    }
+    backups.write_manifest(&executed, "watch")?;
+    let copies_changed =
+        state.record_copies(&changed.copies, &dotfiles_dir.join("symlinks"), &[])?;
+    if copies_changed | state.record_secrets(&executed)? {
+        state.save(&state_path)?;
+    }
+    let secrets_cfg_path = dotfiles_dir.join("cfg").join("secrets");
+    let mut secrets = Register::load(&secrets_cfg_path)?;
+    if secrets.record_permissions(&executed, preserve_owner)? {
+        secrets.save(&secrets_cfg_path)?;
+    }
+    log(&format!("Synced changes: {summary}"));
+    Ok(())
+}
+
+/// Prints a message prefixed with the current time, since `watch` runs unattended.
+fn log(message: &str) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    println!("[{}] {message}", format_timestamp(now));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::register::{Entry, RegisterFormat};
+
+    #[test]
+    fn test_watched_entries() {
+        let register = |entries| Register {
+            entries,
+            format: RegisterFormat::Structured,
+        };
+        let copy = Entry {
+            link_mode: Some(LinkMode::Copy),
+            ..Entry::new(PathBuf::from("/home/me/.config/app/config"))
+        };
+        let symlinks = register(vec![Entry::new(PathBuf::from("/home/me/.vimrc")), copy]);
+        let secrets = register(vec![Entry::new(PathBuf::from("/home/me/.netrc"))]);
+
+        let watched = Watched::new(&symlinks, &secrets, &[]);
+        assert_eq!(
+            watched.paths().collect::<Vec<_>>(),
+            vec![
+                Path::new("/home/me/.netrc"),
+                Path::new("/home/me/.config/app/config")
+            ]
+        );
+
+        let changed = watched.changed(&BTreeSet::from([
+            PathBuf::from("/home/me/.netrc"),
+            PathBuf::from("/home/me/.netrc.swp"),
+        ]));
+        assert_eq!(changed.secrets.entries.len(), 1);
+        assert!(changed.copies.entries.is_empty());
+        assert!(watched.changed(&BTreeSet::new()).is_empty());
+    }
+}